@@ -24,10 +24,13 @@ impl core::fmt::Write for Console {
     }
 }
 
-pub unsafe fn print_c_str(_ptr : *const char) {
+/* Print a NUL-terminated byte string, reading no further than `max` bytes. */
+pub unsafe fn print_c_str(_ptr : *const u8, max : usize) {
     let mut ptr = _ptr;
-    while *ptr != '\0' {
-        putc(*ptr);
+    let mut n   = 0;
+    while n < max && *ptr != 0 {
+        putc(*ptr as char);
         ptr = ptr.offset(1);
+        n  += 1;
     }
 }
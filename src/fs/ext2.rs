@@ -355,4 +355,109 @@ impl Ext2FS {
     pub fn fs_cd(&mut self, name: &str) -> u32 {
         unsafe { return self._fs_cd(name); }
     }
+
+    /* Look a name up in one directory and return its inode number, or 0 if
+       it isn't there. Same walk as _fs_cd, without changing directory. */
+    unsafe fn _lookup(&self, dir_inode: u32, name: &[u8]) -> u32 {
+        let inode_final = self._get_inode(dir_inode);
+        if ((*inode_final).mode & 0xF000) != 0x4000 {
+            return 0;
+        }
+        for j in 0..12 {
+            if (*inode_final).block[j] == 0 {
+                break;
+            }
+            let block = self._get_block((*inode_final).block[j]);
+            let mut off: u32 = 0;
+            while off < self.block_size {
+                let dir = block.offset(off as isize) as *const DirectoryEntry;
+                if (*dir).rec_len == 0 {
+                    break;
+                }
+                if (*dir).inode != 0 && (*dir).name_len as usize == name.len()
+                   && &(*dir).name[..name.len()] == name {
+                    return (*dir).inode;
+                }
+                off += (*dir).rec_len as u32;
+            }
+        }
+        return 0;
+    }
+
+    /* Resolve a path to an inode number, or 0 if it doesn't exist. Absolute
+       paths start at the root directory, anything else starts at the
+       current directory. */
+    pub fn find(&self, path: &[u8]) -> u32 {
+        let mut inode = if path.len() > 0 && path[0] == b'/' { 2 } else { self.c_inode };
+        for part in path.split(|c| *c == b'/') {
+            if part.len() == 0 {
+                continue;
+            }
+            inode = unsafe { self._lookup(inode, part) };
+            if inode == 0 {
+                return 0;
+            }
+        }
+        return inode;
+    }
+
+    pub fn file_size(&self, inode: u32) -> u32 {
+        unsafe { return (*self._get_inode(inode)).size; }
+    }
+
+    /* Map the n-th block of a file to a block on disk. Handles the twelve
+       direct blocks and the singly indirect block, which covers any file
+       that could fit in our RAM anyway. Returns 0 for holes. */
+    unsafe fn _file_block(&self, inode: *const Inode, n: u32) -> u32 {
+        if n < 12 {
+            return (*inode).block[n as usize];
+        }
+        let n = n - 12;
+        if n < self.block_size / 4 && (*inode).block[12] != 0 {
+            let indirect = self._get_block((*inode).block[12]) as *const u32;
+            return *indirect.offset(n as isize);
+        }
+        return 0;
+    }
+
+    /* Copy up to `len` bytes of a file, starting at `offset`, into `buf`.
+       Returns the number of bytes copied. */
+    unsafe fn _read(&self, inode_id: u32, offset: u32, buf: *mut u8, len: u32) -> u32 {
+        let inode = self._get_inode(inode_id);
+        if offset >= (*inode).size {
+            return 0;
+        }
+        let len = core::cmp::min(len, (*inode).size - offset);
+
+        let mut done: u32 = 0;
+        while done < len {
+            let pos = offset + done;
+            let blk_off = pos % self.block_size;
+            let n = core::cmp::min(self.block_size - blk_off, len - done);
+            let blk = self._file_block(inode, pos / self.block_size);
+            if blk == 0 {
+                core::ptr::write_bytes(buf.offset(done as isize), 0, n as usize);
+            } else {
+                core::ptr::copy_nonoverlapping(self._get_block(blk).offset(blk_off as isize),
+                                               buf.offset(done as isize), n as usize);
+            }
+            done += n;
+        }
+        return done;
+    }
+
+    pub fn read(&self, inode: u32, offset: u32, buf: *mut u8, len: u32) -> u32 {
+        unsafe { return self._read(inode, offset, buf, len); }
+    }
+}
+
+/* The filesystem the kernel loads programs from, set up once by main(). */
+static mut ROOT_FS: Option<Ext2FS> = None;
+
+pub fn mount(fs: Ext2FS) {
+    unsafe { ROOT_FS = Some(fs); }
+}
+
+pub fn root() -> Option<&'static mut Ext2FS> {
+    unsafe { return ROOT_FS.as_mut(); }
 }
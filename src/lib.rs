@@ -1,8 +1,10 @@
 #![allow(warnings)]
-#![feature(panic_info_message,allocator_api,asm,lang_items,compiler_builtins_lib,const_raw_ptr_to_usize_cast)]
+#![feature(panic_info_message,allocator_api,alloc_error_handler,asm,lang_items,compiler_builtins_lib,const_raw_ptr_to_usize_cast)]
 //We are not permitted to use the standard library since it isn't written for our operating system
 #![no_std]
 
+extern crate alloc;
+
 
 /**************************************************************
   Macros
@@ -53,6 +55,7 @@ mod mem;
 mod scheduler;
 mod libs;
 mod fs;
+mod loader;
 use crate::mem::heap::{*};
 use core::fmt::Write;
 use crate::atomics::barrier as barrier;
@@ -61,6 +64,7 @@ use crate::mem::pmp::PMP_MODES as pmp_modes;
 use crate::utils::rbtree::rbtree;
 use crate::syscalls::{do_msyscall, UMODE, MMODE, _MMODE_SWITCH, _UMODE_SWITCH};
 use crate::libs::syscalls::{*};
+use crate::libs::env as env;
 use crate::fs::ext2 as ext2;
 
 //The eh_personality tells our program how to unwind. We aren't going to write that, so tell
@@ -88,20 +92,32 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     abort()
 }
 
+//Runs when a Box, Vec etc. can't get memory from the kernel heap.
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    println!("out of memory allocating {} bytes", layout.size());
+    abort()
+}
+
 #[no_mangle]
-unsafe fn proc_a() -> !{
+unsafe fn proc_a(argc : u32, argv : *const *const u8, envp : *const *const u8) -> !{
+    let greeting = env::getenv(envp, "GREETING").unwrap_or("IN A");
     loop{
         asm!("wfi");
-        println!("IN A");
+        println!("{}", greeting);
     }
 }
 
 #[no_mangle]
 unsafe fn init() -> !{
-    let pid = spawn(2048, proc_a as u32, 1, core::ptr::null::<u32>() as *mut u32, 0, "a");
-    let pid = spawn(2048, proc_b as u32, 1, core::ptr::null::<u32>() as *mut u32, 0, "b");
+    let pid = spawn(2048, proc_a as u32, 1, &["a"], &[("GREETING", "IN A")], "a", 0);
+    let pid = spawn(2048, proc_b as u32, 1, &["b"], &[], "b", 0);
+    /* init inherits every orphan, so it spends its life reaping them */
     loop{
-        asm!("wfi");
+        let mut status = 0;
+        if waitpid(syscalls::WAIT_ANY, &mut status) == -1 {
+            sleep(1);
+        }
     }
 }
 
@@ -155,6 +171,7 @@ fn main() -> () {
         _   => "Undefined error",
     });
     fs.read_directory_inode();
+    ext2::mount(fs);
     /*unsafe {
         let ptr: *const u32 = &mut __fs_start as *const u32;
         println!("{:p}", ptr);
@@ -180,7 +197,7 @@ fn main() -> () {
     // }
 
     unsafe{
-      scheduler::sched.new_process(2048, init as u32, 1, core::ptr::null::<u32>() as *mut u32, 0, "init".as_bytes().as_ptr() as *mut char);
+      scheduler::sched.new_process(2048, init as u32, 1, core::ptr::null::<syscalls::proc_args>(), b"init", 0, core::ptr::null_mut(), 0);
       asm!("li t1, 0x80\ncsrs mie, t1":::"t1":"volatile");
       asm!("li t1, 0x8\ncsrs mstatus, t1":::"t1":"volatile");
       mecall(0);
@@ -0,0 +1,40 @@
+/* Reading the arguments and environment a process was started with.
+ * Every process entry point receives
+ *   argc : u32, argv : *const *const u8, envp : *const *const u8
+ * where both arrays are NULL terminated and hold NUL-terminated strings,
+ * and each envp string looks like "KEY=VALUE". */
+
+/* View a NUL-terminated string on the stack as a &str. */
+pub unsafe fn c_str(ptr : *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.offset(len) != 0 {
+        len += 1;
+    }
+    return core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len as usize));
+}
+
+/* The i-th argument, if there is one. */
+pub unsafe fn arg(argc : u32, argv : *const *const u8, i : u32) -> Option<&'static str> {
+    if argv.is_null() || i >= argc {
+        return None;
+    }
+    return Some(c_str(*argv.offset(i as isize)));
+}
+
+/* The value of environment variable `key`, if it is set. */
+pub unsafe fn getenv(envp : *const *const u8, key : &str) -> Option<&'static str> {
+    if envp.is_null() {
+        return None;
+    }
+
+    let mut ptr = envp;
+    while !(*ptr).is_null() {
+        let entry = c_str(*ptr);
+        if entry.len() > key.len() && entry.starts_with(key)
+           && entry.as_bytes()[key.len()] == b'=' {
+            return Some(&entry[key.len() + 1..]);
+        }
+        ptr = ptr.offset(1);
+    }
+    return None;
+}
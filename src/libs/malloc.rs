@@ -0,0 +1,115 @@
+/* The user-space allocator.
+ * A process's blocks live in its arena, which the kernel grows and shrinks
+ * with BRK. Everything the allocator knows is kept in the arena itself,
+ * since processes linked into the kernel image share its statics. Threads
+ * share their process's arena and nothing here locks it, so only one thread
+ * of a process should allocate at a time.
+ *
+ * The arena is a run of blocks from its base up to the break, each with an
+ * 8-byte header:
+ *
+ *   base | size used | data ... | size used | data ... | break
+ *
+ * alloc takes the first free block that fits, splitting it if there is
+ * room left over, and grows the arena when none does. free merges the
+ * block with free neighbours and gives a free run at the end back.
+ */
+use crate::libs::syscalls::{ brk, arena };
+
+#[repr(C)]
+struct block {
+    /* Bytes, header included, always a multiple of HDR */
+    size : u32,
+    used : u32,
+}
+
+const HDR : u32 = core::mem::size_of::<block>() as u32;
+
+/* Returns an 8-byte-aligned block of at least `size` bytes, or NULL if the
+ * arena cannot grow to fit it. */
+pub fn alloc(size : u32) -> *mut u32 { unsafe {
+    if size == 0 {
+        return core::ptr::null::<u32>() as *mut u32;
+    }
+    let need = match size.checked_add(2 * HDR - 1) {
+        Some(n) => n & !(HDR - 1),
+        None    => return core::ptr::null::<u32>() as *mut u32,
+    };
+    let (base, end) = arena();
+    if base == 0 {
+        return core::ptr::null::<u32>() as *mut u32;
+    }
+
+    let mut addr = base;
+    let mut last = 0;
+    while addr < end {
+        let b = addr as *mut block;
+        if (*b).used == 0 && (*b).size >= need {
+            if (*b).size - need >= 2 * HDR {
+                let rest = (addr + need) as *mut block;
+                (*rest).size = (*b).size - need;
+                (*rest).used = 0;
+                (*b).size    = need;
+            }
+            (*b).used = 1;
+            return (addr + HDR) as *mut u32;
+        }
+        last = addr;
+        addr += (*b).size;
+    }
+
+    /* Nothing fits, so grow the arena, building on a free block at the end */
+    let start = if last != 0 && (*(last as *mut block)).used == 0 { last } else { end };
+    let top = match start.checked_add(need) {
+        Some(top) => top,
+        None      => return core::ptr::null::<u32>() as *mut u32,
+    };
+    if brk(top) != top {
+        return core::ptr::null::<u32>() as *mut u32;
+    }
+    let b = start as *mut block;
+    (*b).size = need;
+    (*b).used = 1;
+    return (start + HDR) as *mut u32;
+}
+}
+
+/* Frees the block that you give it. Pointers that aren't the start of a
+ * block from alloc are ignored. */
+pub fn free<T>(addr : *mut T) { unsafe {
+    if addr.is_null() {
+        return;
+    }
+    let target = addr as u32 - HDR;
+    let (base, end) = arena();
+
+    /* Walk from the start, so only real headers are ever touched, merging
+     * runs of free blocks along the way */
+    let mut at  = base;
+    let mut run = 0;
+    while at < end {
+        let b    = at as *mut block;
+        let size = (*b).size;
+        if size < HDR {
+            break;
+        }
+        if at == target {
+            (*b).used = 0;
+        }
+        if (*b).used == 0 {
+            if run == 0 {
+                run = at;
+            } else {
+                (*(run as *mut block)).size += size;
+            }
+        } else {
+            run = 0;
+        }
+        at += size;
+    }
+
+    if run != 0 {
+        brk(run);
+    }
+}
+}
@@ -1 +1,3 @@
 pub mod syscalls;
+pub mod env;
+pub mod malloc;
@@ -1,25 +1,68 @@
 
 use crate::syscalls::{*};
+use crate::scheduler::PROC_NAME_LEN;
+use crate::loader::MAX_ARGS;
+use crate::loader::elf::LoadError;
 
-pub fn exit() { unsafe { syscall(EXIT, 0, 0, 0, 0, 0, 0); } }
+pub fn exit(status : i32) { unsafe { syscall(EXIT, status as u32, 0, 0, 0, 0, 0); } }
 
-pub fn alloc(size: u32) -> *mut u32 { unsafe {
-    return syscall(ALLOC, size, 0, 0, 0, 0, 0) as *mut u32;
+/* Read console input into `buf`, blocking until there is some. Returns
+ * the number of bytes read. */
+pub fn read(buf : &mut [u8]) -> u32 { unsafe {
+    return syscall(READ, buf.as_mut_ptr() as u32, buf.len() as u32, 0, 0, 0, 0);
 }
 }
 
-pub fn free<T>(addr: *mut T) { unsafe {
-    syscall(FREE, addr as u32, 0, 0, 0, 0, 0);
+/* alloc and free run in user space, on top of the arena brk manages */
+pub use crate::libs::malloc::{ alloc, free };
+
+/* Move the end of our arena to `addr`. Returns the new end, or the old one
+ * if it could not be moved there. */
+pub fn brk(addr : u32) -> u32 { unsafe {
+    return syscall(BRK, addr, 0, 0, 0, 0, 0);
+}
+}
+
+/* The start and current end of our arena, or zeroes if we can't have one. */
+pub fn arena() -> (u32, u32) { unsafe {
+    let mut base : u32 = 0;
+    let end = syscall(BRK, 0, &mut base as *mut u32 as u32, 0, 0, 0, 0);
+    return (base, end);
+}
 }
+
+/* Copy a name into a NUL-terminated buffer the kernel can read safely. */
+fn name_buf(name : &str) -> [u8; PROC_NAME_LEN] {
+    let mut buf = [0u8; PROC_NAME_LEN];
+    let len = core::cmp::min(name.len(), PROC_NAME_LEN - 1);
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    return buf;
 }
 
-pub fn spawn(stack_size : u32, ip : u32, QM : u32, data : *mut u32, data_len : u32, name : &'static str) -> i32 { unsafe {
-    return syscall(SPAWN, stack_size, ip, QM, data as u32, data_len, name.as_bytes().as_ptr() as u32) as i32;
+/* Start a process at `ip`. It is entered as
+ *   fn(argc : u32, argv : *const *const u8, envp : *const *const u8)
+ * with `args` in argv and `env` as "KEY=VALUE" strings in envp; see
+ * libs::env for reading them. It may hold `mem_limit` bytes of memory,
+ * stacks included, or as much as we may if that is 0. Returns the new PID
+ * or a negative value. */
+pub fn spawn(stack_size : u32, ip : u32, QM : u32, args : &[&str], env : &[(&str, &str)], name : &str, mem_limit : u32) -> i32 { unsafe {
+    let name = name_buf(name);
+    let empty = user_str { ptr : core::ptr::null::<u8>(), len : 0 };
+    let mut argv = [empty; MAX_ARGS as usize];
+    let mut envp = [empty; 2 * MAX_ARGS as usize];
+    let pargs = match build_args(args, env, &mut argv, &mut envp) {
+        Some(a) => a,
+        None    => return LoadError::ArgsTooBig.code(),
+    };
+    return syscall(SPAWN, stack_size, ip, QM, &pargs as *const proc_args as u32, name.as_ptr() as u32, mem_limit) as i32;
 }
 }
 
-pub fn waitpid(pid : i32) -> i32 { unsafe {
-    return syscall(WAITPID, pid as u32, 0, 0, 0, 0, 0) as i32;
+/* Wait for the child `pid` (or any child with WAIT_ANY) to exit. Returns the
+ * child's PID and stores its exit status in `status`, or -1 if there is no
+ * such child. */
+pub fn waitpid(pid : i32, status : &mut i32) -> i32 { unsafe {
+    return syscall(WAITPID, pid as u32, status as *mut i32 as u32, 0, 0, 0, 0) as i32;
 }
 }
 
@@ -33,8 +76,10 @@ pub fn nproc() -> u32 { unsafe {
 }
 }
 
-pub fn procs() -> *const process_info { unsafe {
-    return syscall(PROCS, 0, 0, 0, 0, 0, 0) as *const process_info;
+/* Fill `out` with the details of as many processes as fit. Returns how many
+ * it filled in; nproc says how many there are. */
+pub fn procs(out : &mut [process_info]) -> usize { unsafe {
+    return syscall(PROCS, out.as_mut_ptr() as u32, out.len() as u32, 0, 0, 0, 0) as usize;
 }
 }
 
@@ -42,3 +87,119 @@ pub fn sleep(secs : u32) { unsafe {
     syscall(SLEEP, secs, 0, 0, 0, 0, 0);
 }
 }
+
+/* Ask for `runtime` ticks every `period` ticks, done within `deadline` ticks.
+ * Returns 0 if admitted. */
+pub fn set_deadline(runtime : u32, period : u32, deadline : u32) -> u32 { unsafe {
+    return syscall(SETDEADLINE, runtime, period, deadline, 0, 0, 0);
+}
+}
+
+/* Accounting for `pid`, or for the caller if pid is -1. Returns 0 on success. */
+pub fn getrusage(pid : i32, out : &mut rusage) -> u32 { unsafe {
+    return syscall(GETRUSAGE, pid as u32, out as *mut rusage as u32, 0, 0, 0, 0);
+}
+}
+
+pub fn sysusage(out : &mut sysusage) -> u32 { unsafe {
+    return syscall(SYSUSAGE, out as *mut sysusage as u32, 0, 0, 0, 0, 0);
+}
+}
+
+/* How much kernel and user memory is in use. With `dump`, the kernel also
+ * prints a map of every block to the console. Returns 0 on success. */
+pub fn meminfo(out : &mut meminfo, dump : bool) -> u32 { unsafe {
+    return syscall(MEMINFO, out as *mut meminfo as u32, dump as u32, 0, 0, 0, 0);
+}
+}
+
+pub fn setname(name : &str) -> u32 { unsafe {
+    return syscall(SETNAME, name.as_ptr() as u32, name.len() as u32, 0, 0, 0, 0);
+}
+}
+
+/* Name of `pid` (or the caller for -1) copied into `buf`. Returns the length
+ * of the full name, or -1 if there is no such process. */
+pub fn getname(pid : i32, buf : &mut [u8]) -> i32 { unsafe {
+    return syscall(GETNAME, pid as u32, buf.as_mut_ptr() as u32, buf.len() as u32, 0, 0, 0) as i32;
+}
+}
+
+/* Build the argument block the kernel expects. `argv` and `env_buf` must
+ * outlive the syscall, so the caller owns them. */
+fn build_args(args : &[&str], env : &[(&str, &str)],
+              argv : &mut [user_str; MAX_ARGS as usize],
+              envp : &mut [user_str; 2 * MAX_ARGS as usize]) -> Option<proc_args> {
+    if args.len() > MAX_ARGS as usize || env.len() > MAX_ARGS as usize {
+        return None;
+    }
+    for (i, a) in args.iter().enumerate() {
+        argv[i] = user_str { ptr : a.as_ptr(), len : a.len() as u32 };
+    }
+    for (i, (k, v)) in env.iter().enumerate() {
+        envp[2 * i]     = user_str { ptr : k.as_ptr(), len : k.len() as u32 };
+        envp[2 * i + 1] = user_str { ptr : v.as_ptr(), len : v.len() as u32 };
+    }
+    return Some(proc_args {
+        argv : argv.as_ptr(),
+        argc : args.len() as u32,
+        envp : envp.as_ptr(),
+        envc : env.len() as u32
+    });
+}
+
+/* Start the ELF executable at `path` with the given arguments and
+ * environment, and a memory limit as for spawn. Returns the new PID or a
+ * negative error code. */
+pub fn spawn_path(path : &str, args : &[&str], env : &[(&str, &str)], stack_size : u32, QM : u32, mem_limit : u32) -> i32 { unsafe {
+    let empty = user_str { ptr : core::ptr::null::<u8>(), len : 0 };
+    let mut argv = [empty; MAX_ARGS as usize];
+    let mut envp = [empty; 2 * MAX_ARGS as usize];
+    let pargs = match build_args(args, env, &mut argv, &mut envp) {
+        Some(a) => a,
+        None    => return LoadError::ArgsTooBig.code(),
+    };
+    return syscall(SPAWN_PATH, path.as_ptr() as u32, path.len() as u32,
+                   &pargs as *const proc_args as u32, stack_size, QM, mem_limit) as i32;
+}
+}
+
+/* Start an instance of the execute-in-place program `name` from flash,
+ * with a memory limit as for spawn. Returns the new PID or a negative
+ * error code. */
+pub fn spawn_flash(name : &str, args : &[&str], env : &[(&str, &str)], stack_size : u32, QM : u32, mem_limit : u32) -> i32 { unsafe {
+    let empty = user_str { ptr : core::ptr::null::<u8>(), len : 0 };
+    let mut argv = [empty; MAX_ARGS as usize];
+    let mut envp = [empty; 2 * MAX_ARGS as usize];
+    let pargs = match build_args(args, env, &mut argv, &mut envp) {
+        Some(a) => a,
+        None    => return LoadError::ArgsTooBig.code(),
+    };
+    return syscall(SPAWN_FLASH, name.as_ptr() as u32, name.len() as u32,
+                   &pargs as *const proc_args as u32, stack_size, QM, mem_limit) as i32;
+}
+}
+
+/* Where new threads start: run the thread function and exit with its
+ * return value. */
+unsafe extern "C" fn thread_entry(f : u32, arg : u32) -> ! {
+    let f : fn(u32) -> i32 = core::mem::transmute(f as usize);
+    thread_exit(f(arg));
+    loop {}
+}
+
+/* Run `f(arg)` in a new thread of this process on a stack of `stack_size`
+ * bytes. Returns the thread ID, or -1. */
+pub fn thread_create(f : fn(u32) -> i32, arg : u32, stack_size : u32) -> i32 { unsafe {
+    return syscall(THREAD_CREATE, thread_entry as u32, f as u32, arg, stack_size, 0, 0) as i32;
+}
+}
+
+/* Wait for thread `tid` to finish and store what it returned in `status`.
+ * Returns 0, or -1 if there is no such thread in this process. */
+pub fn thread_join(tid : i32, status : &mut i32) -> i32 { unsafe {
+    return syscall(THREAD_JOIN, tid as u32, status as *mut i32 as u32, 0, 0, 0, 0) as i32;
+}
+}
+
+pub fn thread_exit(status : i32) { unsafe { syscall(THREAD_EXIT, status as u32, 0, 0, 0, 0, 0); } }
@@ -0,0 +1,126 @@
+/* elf.rs
+ *
+ * The parts of the ELF32 format the loader needs: the file header, program
+ * headers, the dynamic section and RELA relocations. Layouts follow the
+ * System V ABI and the RISC-V ELF psABI.
+ */
+
+pub const ELF_MAGIC   : [u8; 4] = [0x7F, b'E', b'L', b'F'];
+pub const ELFCLASS32  : u8  = 1;
+pub const ELFDATA2LSB : u8  = 1;
+pub const EV_CURRENT  : u8  = 1;
+
+pub const ET_EXEC     : u16 = 2;
+pub const ET_DYN      : u16 = 3;
+pub const EM_RISCV    : u16 = 243;
+
+pub const PT_LOAD     : u32 = 1;
+pub const PT_DYNAMIC  : u32 = 2;
+
+pub const DT_NULL     : u32 = 0;
+pub const DT_RELA     : u32 = 7;
+pub const DT_RELASZ   : u32 = 8;
+pub const DT_RELAENT  : u32 = 9;
+
+pub const R_RISCV_NONE     : u32 = 0;
+pub const R_RISCV_RELATIVE : u32 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf32_Ehdr {
+    pub e_ident     : [u8; 16],
+    pub e_type      : u16,
+    pub e_machine   : u16,
+    pub e_version   : u32,
+    pub e_entry     : u32,
+    pub e_phoff     : u32,
+    pub e_shoff     : u32,
+    pub e_flags     : u32,
+    pub e_ehsize    : u16,
+    pub e_phentsize : u16,
+    pub e_phnum     : u16,
+    pub e_shentsize : u16,
+    pub e_shnum     : u16,
+    pub e_shstrndx  : u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf32_Phdr {
+    pub p_type   : u32,
+    pub p_offset : u32,
+    pub p_vaddr  : u32,
+    pub p_paddr  : u32,
+    pub p_filesz : u32,
+    pub p_memsz  : u32,
+    pub p_flags  : u32,
+    pub p_align  : u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf32_Dyn {
+    pub d_tag : u32,
+    pub d_val : u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Elf32_Rela {
+    pub r_offset : u32,
+    pub r_info   : u32,
+    pub r_addend : i32,
+}
+
+impl Elf32_Rela {
+    pub fn r_type(&self) -> u32 { self.r_info & 0xFF }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadError {
+    NotFound,
+    BadMagic,
+    NotRiscv32,
+    NotPositionIndependent,
+    BadSegment,
+    BadRelocation,
+    ArgsTooBig,
+    NoMemory,
+}
+
+impl LoadError {
+    /* Negative codes handed back to user space. */
+    pub fn code(&self) -> i32 {
+        match self {
+            LoadError::NotFound               => -1,
+            LoadError::BadMagic               => -2,
+            LoadError::NotRiscv32             => -3,
+            LoadError::NotPositionIndependent => -4,
+            LoadError::BadSegment             => -5,
+            LoadError::BadRelocation          => -6,
+            LoadError::ArgsTooBig             => -7,
+            LoadError::NoMemory               => -8,
+        }
+    }
+}
+
+/* Check that this is a little-endian RV32 executable we know how to place
+ * anywhere in memory. Fixed-address (ET_EXEC) images are refused: there is
+ * no MMU to put them where they were linked. */
+pub fn validate(ehdr: &Elf32_Ehdr) -> Result<(), LoadError> {
+    if ehdr.e_ident[0..4] != ELF_MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    if ehdr.e_ident[4] != ELFCLASS32 || ehdr.e_ident[5] != ELFDATA2LSB
+       || ehdr.e_ident[6] != EV_CURRENT || ehdr.e_machine != EM_RISCV {
+        return Err(LoadError::NotRiscv32);
+    }
+    if ehdr.e_type == ET_EXEC {
+        return Err(LoadError::NotPositionIndependent);
+    }
+    if ehdr.e_type != ET_DYN
+       || ehdr.e_phentsize as usize != core::mem::size_of::<Elf32_Phdr>() {
+        return Err(LoadError::BadMagic);
+    }
+    return Ok(());
+}
@@ -0,0 +1,271 @@
+/* loader
+ *
+ * Starts programs stored as ELF executables on the ext2 image. The whole
+ * file is position independent: every PT_LOAD segment is copied into one
+ * block of user memory, .bss is zeroed, R_RISCV_RELATIVE relocations are applied
+ * against the block's address, and the result is started as a new process
+ * with argc/argv/envp on its stack.
+ *
+ * User programs should be linked with -pie and a small max-page-size; the
+ * image is only aligned to a user memory page.
+ *
+ * Programs that run in place from flash are handled by xip.rs.
+ */
+
+pub mod elf;
+pub mod xip;
+
+use crate::console;
+use core::fmt::Write;
+use crate::fs::ext2;
+use crate::mem::user::{ ualloc, ufree };
+use crate::scheduler::{ sched, PCB };
+use crate::syscalls::{ proc_args, user_str };
+use crate::loader::elf::{*};
+
+const MAX_PHDRS : usize = 8;
+/* Upper bound on argv and environment entries */
+pub const MAX_ARGS : u32 = 16;
+/* Upper bound on the length of one argument, key or value */
+pub const MAX_ARG_LEN : u32 = 256;
+
+/* Load `path` and start it with a memory limit of `mem_limit` (see
+ * new_process). Returns the new PID. */
+pub unsafe fn spawn_path(path: &[u8], args: *const proc_args, stack_size: u32, QM: u32, mem_limit: u32) -> Result<i32, LoadError> {
+    let fs = match ext2::root() {
+        Some(fs) => fs,
+        None     => return Err(LoadError::NotFound),
+    };
+
+    let inode = fs.find(path);
+    if inode == 0 {
+        return Err(LoadError::NotFound);
+    }
+    let size = fs.file_size(inode);
+
+    let mut ehdr: Elf32_Ehdr = core::mem::zeroed();
+    let ehdr_size = core::mem::size_of::<Elf32_Ehdr>() as u32;
+    if fs.read(inode, 0, &mut ehdr as *mut Elf32_Ehdr as *mut u8, ehdr_size) != ehdr_size {
+        return Err(LoadError::BadMagic);
+    }
+    validate(&ehdr)?;
+
+    /* Read the program headers and work out how much memory the image spans */
+    let phnum = ehdr.e_phnum as usize;
+    if phnum == 0 || phnum > MAX_PHDRS {
+        return Err(LoadError::BadSegment);
+    }
+    let mut phdrs: [Elf32_Phdr; MAX_PHDRS] = core::mem::zeroed();
+    let phdr_size = core::mem::size_of::<Elf32_Phdr>() as u32;
+    for i in 0..phnum {
+        let off = ehdr.e_phoff + (i as u32) * phdr_size;
+        if fs.read(inode, off, &mut phdrs[i] as *mut Elf32_Phdr as *mut u8, phdr_size) != phdr_size {
+            return Err(LoadError::BadSegment);
+        }
+    }
+
+    let mut lo = core::u32::MAX;
+    let mut hi = 0;
+    for ph in phdrs[..phnum].iter() {
+        if ph.p_type != PT_LOAD {
+            continue;
+        }
+        let end = match ph.p_vaddr.checked_add(ph.p_memsz) {
+            Some(end) => end,
+            None      => return Err(LoadError::BadSegment),
+        };
+        if ph.p_filesz > ph.p_memsz || ph.p_offset.checked_add(ph.p_filesz).map_or(true, |e| e > size) {
+            return Err(LoadError::BadSegment);
+        }
+        lo = core::cmp::min(lo, ph.p_vaddr);
+        hi = core::cmp::max(hi, end);
+    }
+    if lo >= hi || ehdr.e_entry < lo || ehdr.e_entry >= hi {
+        return Err(LoadError::BadSegment);
+    }
+
+    /* Refuse arguments that won't fit before anything is allocated */
+    if args_size(args)? > stack_size / 2 {
+        return Err(LoadError::ArgsTooBig);
+    }
+
+    let span  = hi - lo;
+    let image = ualloc(span) as *mut u8;
+    if image.is_null() {
+        sched.oom();
+        return Err(LoadError::NoMemory);
+    }
+
+    /* Zeroing the whole block takes care of .bss */
+    core::ptr::write_bytes(image, 0, span as usize);
+    for ph in phdrs[..phnum].iter() {
+        if ph.p_type == PT_LOAD && ph.p_filesz > 0
+           && fs.read(inode, ph.p_offset, image.offset((ph.p_vaddr - lo) as isize), ph.p_filesz) != ph.p_filesz {
+            ufree(image as *mut u32, span);
+            return Err(LoadError::BadSegment);
+        }
+    }
+
+    let base = (image as u32).wrapping_sub(lo);
+    for ph in phdrs[..phnum].iter() {
+        if ph.p_type == PT_DYNAMIC {
+            if let Err(e) = relocate(image, lo, hi, base, ph) {
+                ufree(image as *mut u32, span);
+                return Err(e);
+            }
+        }
+    }
+
+    let name = match path.iter().rposition(|c| *c == b'/') {
+        Some(i) => &path[i + 1..],
+        None    => path,
+    };
+
+    let pid = sched.new_process(stack_size, base.wrapping_add(ehdr.e_entry), QM, args, name,
+                                mem_limit, image as *mut u32, span);
+    if pid < 0 {
+        ufree(image as *mut u32, span);
+        return Err(LoadError::NoMemory);
+    }
+
+    return Ok(pid);
+}
+
+/* Apply the RELA table named by a PT_DYNAMIC segment. Only relative
+ * relocations are supported, which is all a static PIE should contain. */
+unsafe fn relocate(image: *mut u8, lo: u32, hi: u32, base: u32, dynamic: &Elf32_Phdr) -> Result<(), LoadError> {
+    let in_image = |addr: u32, len: u32| addr >= lo && addr.checked_add(len).map_or(false, |e| e <= hi);
+
+    let dyn_size = core::mem::size_of::<Elf32_Dyn>() as u32;
+    if !in_image(dynamic.p_vaddr, dynamic.p_memsz) {
+        return Err(LoadError::BadSegment);
+    }
+
+    let mut rela    = 0;
+    let mut relasz  = 0;
+    let mut relaent = core::mem::size_of::<Elf32_Rela>() as u32;
+    let dyns = image.offset((dynamic.p_vaddr - lo) as isize) as *const Elf32_Dyn;
+    for i in 0..(dynamic.p_memsz / dyn_size) {
+        let d = dyns.offset(i as isize).read_unaligned();
+        match d.d_tag {
+            DT_NULL    => break,
+            DT_RELA    => rela    = d.d_val,
+            DT_RELASZ  => relasz  = d.d_val,
+            DT_RELAENT => relaent = d.d_val,
+            _          => {}
+        }
+    }
+
+    if rela == 0 || relasz == 0 {
+        return Ok(());
+    }
+    if relaent != core::mem::size_of::<Elf32_Rela>() as u32 || !in_image(rela, relasz) {
+        return Err(LoadError::BadRelocation);
+    }
+
+    let relas = image.offset((rela - lo) as isize) as *const Elf32_Rela;
+    for i in 0..(relasz / relaent) {
+        let r = relas.offset(i as isize).read_unaligned();
+        match r.r_type() {
+            R_RISCV_NONE     => {},
+            R_RISCV_RELATIVE => {
+                if !in_image(r.r_offset, 4) {
+                    return Err(LoadError::BadRelocation);
+                }
+                let target = image.offset((r.r_offset - lo) as isize) as *mut u32;
+                target.write_unaligned(base.wrapping_add(r.r_addend as u32));
+            },
+            _                => return Err(LoadError::BadRelocation),
+        }
+    }
+    return Ok(());
+}
+
+/* Bytes of stack that push_args will use for `args`. The environment is a
+ * list of (key, value) pairs that end up as "KEY=VALUE" strings. */
+pub unsafe fn args_size(args: *const proc_args) -> Result<u32, LoadError> {
+    if args.is_null() {
+        return Ok(4 * 3 + 16);
+    }
+    let a = &*args;
+    if a.argc > MAX_ARGS || a.envc > MAX_ARGS {
+        return Err(LoadError::ArgsTooBig);
+    }
+
+    /* The lengths come from user space, so every sum is checked */
+    let mut strings: u32 = 0;
+    let mut add = |s: &user_str| -> Result<(), LoadError> {
+        if s.len > MAX_ARG_LEN {
+            return Err(LoadError::ArgsTooBig);
+        }
+        strings = strings.checked_add(s.len + 1).ok_or(LoadError::ArgsTooBig)?;
+        return Ok(());
+    };
+    for i in 0..a.argc {
+        add(&*a.argv.offset(i as isize))?;
+    }
+    for i in 0..2 * a.envc {
+        add(&*a.envp.offset(i as isize))?;
+    }
+
+    /* argc, argv[] + NULL, envp[] + NULL, and room to align */
+    return strings.checked_add(4 * (3 + a.argc + a.envc) + 16).ok_or(LoadError::ArgsTooBig);
+}
+
+/* Build the initial stack of a new process:
+ *
+ *   sp -> argc
+ *         argv[0] .. argv[argc - 1], NULL
+ *         envp[0] .. envp[envc - 1], NULL
+ *         the strings themselves, up to the top of the stack
+ *
+ * and start it with a0 = argc, a1 = argv, a2 = envp. The caller must have
+ * checked args_size() against the stack size. Every process, whether it was
+ * loaded from a file or spawned from a function, starts like this. */
+pub unsafe fn push_args(pcb: *mut PCB, args: *const proc_args) {
+    let empty = proc_args {
+        argv : core::ptr::null::<user_str>(),
+        argc : 0,
+        envp : core::ptr::null::<user_str>(),
+        envc : 0,
+    };
+    let a = if args.is_null() { &empty } else { &*args };
+
+    let top = (*pcb).stack_pointer + (*pcb).stack_size;
+    let strings = args_size(args).unwrap_or(0) - 4 * (3 + a.argc + a.envc) - 16;
+    let sp = (top - strings - 4 * (3 + a.argc + a.envc)) & !0xF;
+
+    let argv = (sp + 4) as *mut u32;
+    let envp = argv.offset(a.argc as isize + 1);
+    let mut dst = envp.offset(a.envc as isize + 1) as *mut u8;
+
+    let mut copy = |src: &user_str, dst: &mut *mut u8| {
+        core::ptr::copy_nonoverlapping(src.ptr, *dst, src.len as usize);
+        *dst = dst.offset(src.len as isize);
+    };
+
+    for i in 0..a.argc {
+        *argv.offset(i as isize) = dst as u32;
+        copy(&*a.argv.offset(i as isize), &mut dst);
+        *dst = 0;
+        dst = dst.offset(1);
+    }
+    *argv.offset(a.argc as isize) = 0;
+
+    for i in 0..a.envc {
+        *envp.offset(i as isize) = dst as u32;
+        copy(&*a.envp.offset(2 * i as isize), &mut dst);
+        *dst = b'=';
+        dst = dst.offset(1);
+        copy(&*a.envp.offset(2 * i as isize + 1), &mut dst);
+        *dst = 0;
+        dst = dst.offset(1);
+    }
+    *envp.offset(a.envc as isize) = 0;
+
+    *(sp as *mut u32) = a.argc;
+    (*pcb).context[2]  = sp;
+    (*pcb).context[10] = a.argc;
+    (*pcb).context[11] = argv as u32;
+    (*pcb).context[12] = envp as u32;
+}
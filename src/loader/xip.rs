@@ -0,0 +1,115 @@
+/* xip.rs
+ *
+ * Execute-in-place programs. tools/mkxip.py packs user programs into a
+ * table of contents in flash (see asm/progs.S). Their text runs straight
+ * from flash; each instance gets its own user memory holding .data, .bss and
+ * the GOT, with gp pointing into it, so several copies of one program can
+ * run at once while only paying RAM for their data.
+ */
+
+use crate::mem::user::{ ualloc, ufree };
+use crate::scheduler::sched;
+use crate::syscalls::proc_args;
+use crate::loader::args_size;
+use crate::loader::elf::LoadError;
+
+extern "C" {
+    static __progs_start: u32;
+}
+
+const XIP_MAGIC   : u32 = 0x5450_4958; /* "XIPT" */
+const XIP_NAME_LEN: usize = 16;
+/* Relocations with this bit hold a text offset, the rest a data offset */
+const TEXT_FLAG   : u32 = 0x8000_0000;
+
+#[repr(C)]
+struct XipToc {
+    magic : u32,
+    count : u32,
+}
+
+#[repr(C)]
+struct XipEntry {
+    name       : [u8; XIP_NAME_LEN],
+    text_off   : u32,
+    text_len   : u32,
+    data_off   : u32,
+    data_len   : u32,
+    bss_len    : u32,
+    entry      : u32,
+    gp         : u32,
+    reloc_off  : u32,
+    reloc_count: u32,
+}
+
+unsafe fn find(name: &[u8]) -> *const XipEntry {
+    let toc = &__progs_start as *const u32 as *const XipToc;
+    if (*toc).magic != XIP_MAGIC {
+        return core::ptr::null::<XipEntry>();
+    }
+
+    let entries = toc.offset(1) as *const XipEntry;
+    for i in 0..(*toc).count {
+        let e = entries.offset(i as isize);
+        let len = (*e).name.iter().position(|c| *c == 0).unwrap_or(XIP_NAME_LEN);
+        if &(*e).name[..len] == name {
+            return e;
+        }
+    }
+    return core::ptr::null::<XipEntry>();
+}
+
+/* Start a new instance of the flash program `name` with a memory limit of
+ * `mem_limit` (see new_process). Returns the new PID. */
+pub unsafe fn spawn_flash(name: &[u8], args: *const proc_args, stack_size: u32, QM: u32, mem_limit: u32) -> Result<i32, LoadError> {
+    let e = find(name);
+    if e.is_null() {
+        return Err(LoadError::NotFound);
+    }
+    let e = &*e;
+
+    let ram_len = e.data_len + e.bss_len;
+    if e.entry >= e.text_len || e.gp > ram_len {
+        return Err(LoadError::BadSegment);
+    }
+    if args_size(args)? > stack_size / 2 {
+        return Err(LoadError::ArgsTooBig);
+    }
+
+    let toc  = &__progs_start as *const u32 as *const u8;
+    let text = toc.offset(e.text_off as isize) as u32;
+
+    /* ualloc won't hand out zero bytes, so always ask for at least a word */
+    let data_size = core::cmp::max(ram_len, 4);
+    let data = ualloc(data_size) as *mut u8;
+    if data.is_null() {
+        sched.oom();
+        return Err(LoadError::NoMemory);
+    }
+    core::ptr::copy_nonoverlapping(toc.offset(e.data_off as isize), data, e.data_len as usize);
+    core::ptr::write_bytes(data.offset(e.data_len as isize), 0, e.bss_len as usize);
+
+    let relocs = toc.offset(e.reloc_off as isize) as *const u32;
+    for i in 0..e.reloc_count {
+        let r    = *relocs.offset(i as isize);
+        let word = r & !TEXT_FLAG;
+        if word + 4 > e.data_len {
+            ufree(data as *mut u32, data_size);
+            return Err(LoadError::BadRelocation);
+        }
+        let target = data.offset(word as isize) as *mut u32;
+        let base   = if r & TEXT_FLAG != 0 { text } else { data as u32 };
+        target.write_unaligned(base.wrapping_add(target.read_unaligned()));
+    }
+
+    let pid = sched.new_process(stack_size, text + e.entry, QM, args, name,
+                                mem_limit, data as *mut u32, data_size);
+    let pcb = sched.get_pcb(pid);
+    if pid < 0 || pcb.is_null() {
+        ufree(data as *mut u32, data_size);
+        return Err(LoadError::NoMemory);
+    }
+    (*pcb).context[3] = data as u32 + e.gp;
+
+    return Ok(pid);
+}
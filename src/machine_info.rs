@@ -25,3 +25,36 @@ pub enum ClintRegister {
 pub fn get_clint_register(reg : ClintRegister) -> &'static mut u32 {
     unsafe { ((CLINT_BASE + (reg as u32)) as *mut u32).as_mut().unwrap() }
 }
+
+/* Read the 64-bit mtime counter. The high word is read on both sides of the
+ * low word so a carry between the two reads can't tear the value. */
+pub fn read_mtime() -> u64 {
+    let mtimelo : &mut u32 = get_clint_register(ClintRegister :: MTIMELO);
+    let mtimehi : &mut u32 = get_clint_register(ClintRegister :: MTIMEHI);
+    unsafe {
+        loop {
+            let hi = (mtimehi as *mut u32).read_volatile();
+            let lo = (mtimelo as *mut u32).read_volatile();
+            if hi == (mtimehi as *mut u32).read_volatile() {
+                return ((hi as u64) << 32) | (lo as u64);
+            }
+        }
+    }
+}
+
+/* Read the 64-bit mcycle counter, the same way as read_mtime(). */
+pub fn read_mcycle() -> u64 {
+    unsafe {
+        loop {
+            let mut hi : u32 = 0;
+            let mut lo : u32 = 0;
+            let mut hi2: u32 = 0;
+            asm!("csrr $0, mcycleh" : "=r"(hi)  ::: "volatile");
+            asm!("csrr $0, mcycle"  : "=r"(lo)  ::: "volatile");
+            asm!("csrr $0, mcycleh" : "=r"(hi2) ::: "volatile");
+            if hi == hi2 {
+                return ((hi as u64) << 32) | (lo as u64);
+            }
+        }
+    }
+}
@@ -1,15 +1,27 @@
 /* The heap.
  * This is how the kernel allocates memory to the heap.
- * All process stacks are stored on this heap, as well
- * as user heap allocations.
- * This heap should consume the majority of RAM. The
- * kernel's stack consumes 2K, and this heap should
- * consume the remainder of the machine's 16K.
- * Its mechanisms are simple: initialize by
- * creating a 4-byte header that will serve as the first
- * node in the freelist, and each time an allocation
- * is made, iterate over the freelist and find the first
- * free block of memory.
+ * The heap region consumes the majority of RAM: the
+ * kernel's stack consumes 2K, and the heap region the
+ * remainder of the machine's 16K. The kernel heap below
+ * gets the first half of it, for PCBs, kernel stacks and
+ * everything else the kernel allocates. The rest is user
+ * memory (mem/user.rs), which holds process stacks,
+ * program images and BRK arenas.
+ * Every block starts with a header giving its size and
+ * the size of the block before it, so blocks can be
+ * walked in address order in both directions. Small
+ * heaps like this one pack that into 4 bytes; heaps too
+ * big for that (the 128M on QEMU virt, say) get a wider
+ * header instead, chosen when the heap is initialized.
+ * Free blocks are also kept on segregated free lists
+ * (two-level segregated fit, "TLSF"): a first level per
+ * power of two and a few second-level lists splitting
+ * each power of two, with a bitmap of which lists are
+ * non-empty. Finding a block is a couple of bit scans
+ * rather than a walk over the heap, so kmalloc and kfree
+ * take the same time however many blocks there are,
+ * which matters since the scheduler allocates from
+ * inside the timer interrupt.
  * Fails only when it cannot make the requested allocation.
  * Frees memory by coallescing. It does this by
  * first freeing up the block that was requested, and then
@@ -17,7 +29,10 @@
  * merged. This prevents fragmentation.
  */
 use crate::console as console;
+use crate::mem::user::{ user_init, UPAGE_SIZE };
+use crate::scheduler::sched;
 use core::fmt::Write;
+use core::alloc::{ GlobalAlloc, Layout };
 
 /* These come from the linker script */
 extern "C" {
@@ -43,115 +58,832 @@ extern "C" {
 const taken_mask: u32 = 0x00008000;
 const cur_size_mask: u32 = 0x00007FFF;
 const prev_size_mask: u32 = 0x7FFF0000;
-const max_block_size: u32 = (1 << 15) * 4;
 
-/* Initialize the heap by simply creating a single free node at the beginning of the heap,
- * which is at __heap_start.
- */
-pub fn heap_init() -> () {
-    unsafe {
-        let heap_size: u32 = &__heap_size as *const _ as u32;
-        let ptr: *mut u32 = &mut __heap_start as *mut u32; /* Pointer to the start of the heap */
+/* Heaps too big for the packed header use a wide one instead: two machine
+ * words, the first holding the size in words with the taken bit on top and
+ * the second holding the size of the previous block. It costs a word more
+ * per block on RV32 (three on RV64) but sizes are then only limited by the
+ * free lists. The packed header is also only used on RV32, since on RV64 it
+ * would leave the free list links in a block misaligned. */
+#[repr(C)]
+struct wide_header {
+    size: usize,
+    prev: usize,
+}
+
+const wide_taken_mask: usize = !(!0usize >> 1);
+
+/* A free block keeps its next and previous free-list links in its first
+ * payload words, so no block is ever smaller than that. A previous size of
+ * zero therefore always means "first block in the heap". */
+const min_block_words: u32 = (2 * core::mem::size_of::<usize>() / 4) as u32;
+
+/* Free list geometry. Sizes below SL_COUNT words get a list each; above
+ * that, each power of two is split into SL_COUNT lists. FL_COUNT rows cover
+ * blocks up to 2^(FL_COUNT + SL_BITS - 1) words. */
+const SL_BITS: u32 = 2;
+const SL_COUNT: usize = 1 << SL_BITS;
+const FL_COUNT: usize = 24;
+const max_list_words: u32 = (1 << (FL_COUNT as u32 + SL_BITS - 1)) - 1;
+
+/* With heap_debug, every allocation carries a record of who made it and a
+ * guard word on either side of the caller's bytes:
+ *
+ *   header | caller | pid | size | front guard | data ... | back guard
+ *
+ * The whole heap is walked and checked before every operation, kfree makes
+ * sure it was handed the start of a taken block before touching any lists,
+ * and freed payloads are filled with POISON so stale reads stand out.
+ * Without the feature the record and guards take no space. */
+#[cfg(feature = "heap_debug")]
+#[repr(C)]
+struct debug_record {
+    caller: usize,
+    pid:    i32,
+    size:   u32,
+    /* A machine word, so the data after it stays aligned for one on RV64 */
+    guard:  usize,
+}
+
+#[cfg(feature = "heap_debug")]
+const DEBUG_WORDS: u32 = (core::mem::size_of::<debug_record>() / 4) as u32;
+#[cfg(feature = "heap_debug")]
+const GUARD_WORDS: u32 = 1;
+#[cfg(not(feature = "heap_debug"))]
+const DEBUG_WORDS: u32 = 0;
+#[cfg(not(feature = "heap_debug"))]
+const GUARD_WORDS: u32 = 0;
+
+#[cfg(feature = "heap_debug")]
+const FRONT_GUARD: usize = 0xFEEDFACE;
+#[cfg(feature = "heap_debug")]
+const BACK_GUARD: u32 = 0xCAFEF00D;
+#[cfg(feature = "heap_debug")]
+const POISON: u32 = 0xDEADBEEF;
+
+/* A snapshot of how the heap is being used. Sizes are in bytes. */
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub size          : u32,
+    /* Taken blocks, headers included */
+    pub used          : u32,
+    /* Payload of the free blocks */
+    pub free          : u32,
+    /* Blocks of any kind, and how many of those are free */
+    pub blocks        : u32,
+    pub free_blocks   : u32,
+    pub largest_free  : u32,
+    /* Share of the free space outside the largest free block, per mille.
+     * 0 means every free byte could go to a single allocation. */
+    pub fragmentation : u32,
+    pub allocs        : u32,
+    pub frees         : u32,
+    pub failures      : u32,
+    /* Whether blocks carry the wide header rather than the packed one */
+    pub wide          : bool,
+}
+
+pub struct Heap {
+    start: *mut u32,
+    end: *mut u32,
+    /* Header format, picked by init from the size of the heap. Sizes are
+     * always counted in 32-bit words, not including the header; the free
+     * lists stop at max_list_words, so a u32 holds them on RV64 too.
+     * Addresses are only ever worked on as usize. */
+    wide: bool,
+    hdr_words: u32,
+    /* Block sizes are a multiple of this many words, so that wide headers
+     * stay word aligned on RV64 */
+    unit: u32,
+    max_words: u32,
+    /* Bit f is set if any list in row f is non-empty, and bit s of
+     * sl_bitmap[f] if free[f][s] is */
+    fl_bitmap: u32,
+    sl_bitmap: [u32; FL_COUNT],
+    free: [[*mut u32; SL_COUNT]; FL_COUNT],
+    free_words: u32,
+    free_blocks: u32,
+    allocs: u32,
+    frees: u32,
+    failures: u32,
+    /* Return address of the kmalloc/kfree call being served */
+    #[cfg(feature = "heap_debug")]
+    caller: usize,
+}
 
-        /* Take 4 bytes from the size of the heap, that's this node in the freelist.
-         * Also divide by 4 since the size that we store is the number of words.
-         */
-        let node: u32 = cur_size_mask & (((heap_size - 4) / 4) as u32);
-        ptr.write(node);
+static mut KHEAP: Heap = Heap::new();
 
-        println!("Initializing heap at {:p}, size: {:p}, ends at {:p}", ptr, &__heap_size, &__heap_end);
+/* Which free list a block of `size` words lives on. */
+fn mapping(size: u32) -> (usize, usize) {
+    if size < SL_COUNT as u32 {
+        return (0, size as usize);
     }
+    let fl = 31 - size.leading_zeros();
+    let sl = (size >> (fl - SL_BITS)) as usize & (SL_COUNT - 1);
+    return ((fl - SL_BITS + 1) as usize, sl);
 }
 
-/* Returns either:
- *   1. A pointer to a 4-byte-aligned region of memory at least as large as you requested.
- *   2. A NULL pointer, if the heap has no room.
- */
-pub fn kmalloc(mut size: u32) -> *mut u32 {
-    /* Check the size to make sure it's not too big or zero */
-    if (size == 0) || (size > max_block_size) {
-        return 0 as *mut u32;
+/* The first free list whose blocks are all at least `size` words. */
+fn mapping_search(mut size: u32) -> (usize, usize) {
+    if size >= SL_COUNT as u32 {
+        let fl = 31 - size.leading_zeros();
+        size += (1 << (fl - SL_BITS)) - 1;
+    }
+    return mapping(size);
+}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Heap {
+            start: 0 as *mut u32,
+            end: 0 as *mut u32,
+            wide: false,
+            hdr_words: 1,
+            unit: 1,
+            max_words: 0,
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            free: [[0 as *mut u32; SL_COUNT]; FL_COUNT],
+            free_words: 0,
+            free_blocks: 0,
+            allocs: 0,
+            frees: 0,
+            failures: 0,
+            #[cfg(feature = "heap_debug")]
+            caller: 0,
+        }
+    }
+
+    /* Manage [start, end) as one big free block. The packed header is used
+     * if the heap is small enough for it, the wide one otherwise. */
+    pub unsafe fn init(&mut self, start: *mut u32, end: *mut u32) {
+        *self = Heap::new();
+
+        let words = (end as usize - start as usize) / 4;
+        if core::mem::size_of::<usize>() == 4 && words <= (cur_size_mask + 1) as usize {
+            self.wide      = false;
+            self.hdr_words = 1;
+            self.unit      = 1;
+            self.max_words = cur_size_mask;
+        } else {
+            self.wide      = true;
+            self.hdr_words = (core::mem::size_of::<wide_header>() / 4) as u32;
+            self.unit      = (core::mem::size_of::<usize>() / 4) as u32;
+            self.max_words = max_list_words & !(self.unit - 1);
+        }
+
+        /* Anything past what one header can describe goes unused */
+        let size = core::cmp::min(words - self.hdr_words as usize, self.max_words as usize) as u32
+                 & !(self.unit - 1);
+        self.start = start;
+        self.end   = start.offset((size + self.hdr_words) as isize);
+
+        self.set_hdr(start, false, size, 0);
+        self.insert(start);
+    }
+
+    /* Header accessors. Everything below goes through these, so the rest of
+     * the allocator does not care which format is in use. */
+    unsafe fn hdr_size(&self, hdr: *mut u32) -> u32 {
+        if self.wide {
+            return ((*(hdr as *mut wide_header)).size & !wide_taken_mask) as u32;
+        }
+        return hdr.read() & cur_size_mask;
+    }
+
+    unsafe fn hdr_prev(&self, hdr: *mut u32) -> u32 {
+        if self.wide {
+            return (*(hdr as *mut wide_header)).prev as u32;
+        }
+        return (hdr.read() & prev_size_mask) >> 16;
+    }
+
+    unsafe fn hdr_taken(&self, hdr: *mut u32) -> bool {
+        if self.wide {
+            return ((*(hdr as *mut wide_header)).size & wide_taken_mask) != 0;
+        }
+        return (hdr.read() & taken_mask) != 0;
+    }
+
+    /* Write a header. Sizes are in words. */
+    unsafe fn set_hdr(&self, hdr: *mut u32, taken: bool, size: u32, prevsize: u32) {
+        if self.wide {
+            let wide = hdr as *mut wide_header;
+            (*wide).size = size as usize | (if taken { wide_taken_mask } else { 0 });
+            (*wide).prev = prevsize as usize;
+            return;
+        }
+        hdr.write((if taken { taken_mask } else { 0 }) |
+                  (size & cur_size_mask) |
+                  ((prevsize << 16) & prev_size_mask));
+    }
+
+    unsafe fn set_taken(&self, hdr: *mut u32, taken: bool) {
+        self.set_hdr(hdr, taken, self.hdr_size(hdr), self.hdr_prev(hdr));
+    }
+
+    /* The payload of the block at `hdr`, and back again */
+    unsafe fn payload(&self, hdr: *mut u32) -> *mut u32 {
+        return hdr.offset(self.hdr_words as isize);
+    }
+
+    unsafe fn header(&self, ptr: *mut u32) -> *mut u32 {
+        return ptr.offset(-(self.hdr_words as isize));
+    }
+
+    /* What the caller gets for the block at `hdr`, after any debug record,
+     * and back again */
+    unsafe fn user(&self, hdr: *mut u32) -> *mut u32 {
+        return self.payload(hdr).offset(DEBUG_WORDS as isize);
+    }
+
+    unsafe fn block(&self, ptr: *mut u32) -> *mut u32 {
+        return self.header(ptr.offset(-(DEBUG_WORDS as isize)));
+    }
+
+    /* Round a size in bytes up to whole words, and up to the smallest block.
+     * Makes room for the debug record and guard, if any. */
+    fn words(&self, size: u32) -> u32 {
+        let words = core::cmp::max((size + 3) / 4 + DEBUG_WORDS + GUARD_WORDS, min_block_words);
+        return (words + self.unit - 1) & !(self.unit - 1);
+    }
+
+    /* Whether a request for `size` bytes could ever be met */
+    fn fits(&self, size: u32) -> bool {
+        return size != 0 && size / 4 + DEBUG_WORDS + GUARD_WORDS < self.max_words;
+    }
+
+    unsafe fn links(&self, hdr: *mut u32) -> *mut *mut u32 {
+        return self.payload(hdr) as *mut *mut u32;
+    }
+
+    /* Put a free block on the list for its size. */
+    unsafe fn insert(&mut self, hdr: *mut u32) {
+        let size = self.hdr_size(hdr);
+        let (fl, sl) = mapping(size);
+        let head = self.free[fl][sl];
+        let links = self.links(hdr);
+
+        *links.offset(0) = head;
+        *links.offset(1) = 0 as *mut u32;
+        if !head.is_null() {
+            *self.links(head).offset(1) = hdr;
+        }
+        self.free[fl][sl] = hdr;
+        self.fl_bitmap    |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+
+        self.free_words  += size;
+        self.free_blocks += 1;
+    }
+
+    /* Take a free block off its list. */
+    unsafe fn remove(&mut self, hdr: *mut u32) {
+        let size = self.hdr_size(hdr);
+        let (fl, sl) = mapping(size);
+        let links = self.links(hdr);
+        let next = *links.offset(0);
+        let prev = *links.offset(1);
+
+        if !next.is_null() {
+            *self.links(next).offset(1) = prev;
+        }
+        if prev.is_null() {
+            self.free[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        } else {
+            *self.links(prev).offset(0) = next;
+        }
+
+        self.free_words  -= size;
+        self.free_blocks -= 1;
+    }
+
+    /* Take a free block of at least `size` words off the free lists, or
+     * return NULL. Only looks at lists that are guaranteed to fit, so the
+     * first block found will do. */
+    unsafe fn find(&mut self, size: u32) -> *mut u32 {
+        let (mut fl, sl) = mapping_search(size);
+        if fl >= FL_COUNT {
+            return 0 as *mut u32;
+        }
+
+        let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map == 0 {
+            let fl_map = if fl + 1 >= 32 { 0 } else { self.fl_bitmap & (!0u32 << (fl + 1)) };
+            if fl_map == 0 {
+                return 0 as *mut u32;
+            }
+            fl     = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+
+        let hdr = self.free[fl][sl_map.trailing_zeros() as usize];
+        self.remove(hdr);
+        return hdr;
+    }
+
+    /* The block after `hdr` in memory, or NULL at the end of the heap. */
+    unsafe fn next_phys(&self, hdr: *mut u32) -> *mut u32 {
+        let next = self.payload(hdr).offset(self.hdr_size(hdr) as isize);
+        return if next < self.end { next } else { 0 as *mut u32 };
+    }
+
+    /* The block before `hdr` in memory, or NULL at the start of the heap. */
+    unsafe fn prev_phys(&self, hdr: *mut u32) -> *mut u32 {
+        let prevsize = self.hdr_prev(hdr);
+        return if prevsize == 0 { 0 as *mut u32 } else { self.header(hdr.offset(-(prevsize as isize))) };
+    }
+
+    /* Store the size of the block at `hdr` in the header of the block after
+     * it, so that kfree can find its way back up. */
+    unsafe fn update_next_prev(&self, hdr: *mut u32) {
+        let next = self.next_phys(hdr);
+        if !next.is_null() {
+            self.set_hdr(next, self.hdr_taken(next), self.hdr_size(next), self.hdr_size(hdr));
+        }
     }
 
-    /* Get size to be a multiple of 4,
-     * then divide it by 4.
+    /* Mark a block free, merge it with free neighbours and put the result
+     * on the free lists. */
+    unsafe fn release(&mut self, mut hdr: *mut u32) {
+        let mut size = self.hdr_size(hdr);
+
+        /* Look down to see if we need to merge the block below. */
+        let next = self.next_phys(hdr);
+        if !next.is_null() && !self.hdr_taken(next) {
+            self.remove(next);
+            size += self.hdr_size(next) + self.hdr_words;
+        }
+
+        /* Look up to see if we need to merge the block above. */
+        let prev = self.prev_phys(hdr);
+        if !prev.is_null() && !self.hdr_taken(prev) {
+            self.remove(prev);
+            size += self.hdr_size(prev) + self.hdr_words;
+            hdr   = prev;
+        }
+
+        self.set_hdr(hdr, false, size, self.hdr_prev(hdr));
+        self.update_next_prev(hdr);
+        self.insert(hdr);
+    }
+
+    /* Shrink the taken block at `hdr` to `size` words, freeing the tail if
+     * it is big enough to be a block. If there will only be a few bytes
+     * left, just give them away. */
+    unsafe fn split(&mut self, hdr: *mut u32, size: u32) {
+        let total = self.hdr_size(hdr);
+        if total < size + min_block_words + self.hdr_words {
+            return;
+        }
+
+        self.set_hdr(hdr, true, size, self.hdr_prev(hdr));
+        let rest = self.payload(hdr).offset(size as isize);
+        self.set_hdr(rest, true, total - size - self.hdr_words, size);
+        self.update_next_prev(rest);
+        self.release(rest);
+    }
+
+    /* Returns either:
+     *   1. A pointer to a 4-byte-aligned region of memory at least as large as you requested.
+     *   2. A NULL pointer, if the heap has no room.
      */
-    let rem: u32 = size % 4;
-    if rem != 0 {
-        size = size + (4 - rem);
+    pub unsafe fn alloc(&mut self, size: u32) -> *mut u32 {
+        /* Check the size to make sure it's not too big or zero */
+        if !self.fits(size) {
+            return 0 as *mut u32;
+        }
+        #[cfg(feature = "heap_debug")]
+        {
+            if !self.validate() {
+                return 0 as *mut u32;
+            }
+        }
+        let want = self.words(size);
+
+        let hdr = self.find(want);
+        if hdr.is_null() {
+            self.failures += 1;
+            return 0 as *mut u32;
+        }
+
+        self.set_taken(hdr, true);
+        self.split(hdr, want);
+        #[cfg(feature = "heap_debug")]
+        self.record(hdr, size);
+        self.allocs += 1;
+        return self.user(hdr);
     }
-    size /= 4;
 
-    unsafe {
-        /* Pointer to the start of our heap. */
-        let start: *mut u32 = &mut __heap_start as *mut u32;
-        /* Used to offset the above pointer into the heap */
-        let mut offset: isize = 0;
-        /* Pointer to the end of the heap */
-        let end: *mut u32 = &mut __heap_end as *mut u32;
-        let blocksize: u32;
-        let prevsize: u32;
-        let mut node: u32;
+    /* Like alloc, but the block starts on a multiple of `align`, which must
+     * be a power of two. Any space skipped to get there is split off as a
+     * free block of its own, so it is not lost. */
+    pub unsafe fn alloc_aligned(&mut self, size: u32, align: u32) -> *mut u32 {
+        if align <= 4 {
+            return self.alloc(size);
+        }
+        if !self.fits(size) || !align.is_power_of_two() {
+            return 0 as *mut u32;
+        }
+        #[cfg(feature = "heap_debug")]
+        {
+            if !self.validate() {
+                return 0 as *mut u32;
+            }
+        }
+        let want = self.words(size);
 
-        /* Loop until we find a free block that's big enough */
-        loop {
-            node = start.offset(offset).read();
-            if start.offset(offset) >= end {
-                /* We've hit the end of the heap */
+        /* Room for the block plus the most we might skip to reach an aligned
+         * address with a usable free block in front of it: the skip is
+         * bumped a step of align at a time until the block in front fits,
+         * so it stays under one step past that */
+        let most_slack = min_block_words + self.hdr_words + align / 4;
+        let mut hdr = self.find(want + most_slack);
+        if hdr.is_null() {
+            self.failures += 1;
+            return 0 as *mut u32;
+        }
+        self.set_taken(hdr, true);
+
+        let payload = self.user(hdr) as usize;
+        let align   = align as usize;
+        let mut slack = ((((payload + align - 1) & !(align - 1)) - payload) / 4) as u32;
+        while slack != 0 && slack < min_block_words + self.hdr_words {
+            slack += (align / 4) as u32;
+        }
+
+        if slack != 0 {
+            let total = self.hdr_size(hdr);
+            let block = hdr.offset(slack as isize);
+            let lead  = slack - self.hdr_words;
+            self.set_hdr(block, true, total - slack, lead);
+            self.set_hdr(hdr, true, lead, self.hdr_prev(hdr));
+            self.update_next_prev(block);
+            self.release(hdr);
+            hdr = block;
+        }
+
+        self.split(hdr, want);
+        #[cfg(feature = "heap_debug")]
+        self.record(hdr, size);
+        self.allocs += 1;
+        return self.user(hdr);
+    }
+
+    /* Frees the pointer that you give it. */
+    pub unsafe fn free(&mut self, ptr: *mut u32) {
+        /* Sanity checks */
+        if (ptr <= self.start) || (ptr >= self.end) {
+            println!("ERROR: Pointer was invalid: {:p}", ptr);
+            return;
+        }
+        #[cfg(feature = "heap_debug")]
+        {
+            if !self.check_free(ptr) {
+                return;
+            }
+            self.poison(self.block(ptr));
+        }
+
+        self.frees += 1;
+        self.release(self.block(ptr));
+    }
+
+    /* Resize the allocation at `ptr` to `new_size` bytes, keeping its
+     * contents. Grows in place when the next block is free and big enough,
+     * otherwise moves to a new block, which only keeps word alignment. */
+    pub unsafe fn realloc(&mut self, ptr: *mut u32, new_size: u32) -> *mut u32 {
+        if ptr.is_null() {
+            return self.alloc(new_size);
+        }
+        if new_size == 0 {
+            self.free(ptr);
+            return 0 as *mut u32;
+        }
+        if !self.fits(new_size) {
+            return 0 as *mut u32;
+        }
+        #[cfg(feature = "heap_debug")]
+        {
+            if !self.check_free(ptr) {
                 return 0 as *mut u32;
             }
-            if ((node & taken_mask) == 0) && ((node & cur_size_mask) >= size) {
-                /* We found a block that's:
-                 *   1. Not taken
-                 *   2. Large enough
-                 */
-                break;
+        }
+
+        let hdr  = self.block(ptr);
+        let size = self.hdr_size(hdr);
+        let want = self.words(new_size);
+
+        if want <= size {
+            self.split(hdr, want);
+            #[cfg(feature = "heap_debug")]
+            self.record(hdr, new_size);
+            return ptr;
+        }
+
+        let next = self.next_phys(hdr);
+        if !next.is_null() && !self.hdr_taken(next) && size + self.hdr_size(next) + self.hdr_words >= want {
+            self.remove(next);
+            self.set_hdr(hdr, true, size + self.hdr_size(next) + self.hdr_words, self.hdr_prev(hdr));
+            self.update_next_prev(hdr);
+            self.split(hdr, want);
+            #[cfg(feature = "heap_debug")]
+            self.record(hdr, new_size);
+            return ptr;
+        }
+
+        let new = self.alloc(new_size);
+        if new.is_null() {
+            return 0 as *mut u32;
+        }
+        core::ptr::copy_nonoverlapping(ptr, new, (size - DEBUG_WORDS - GUARD_WORDS) as usize);
+        self.free(ptr);
+        return new;
+    }
+
+    /* How many bytes the block at `ptr` can hold, which may be a little more
+     * than was asked for. */
+    pub unsafe fn usable(&self, ptr: *mut u32) -> u32 {
+        return (self.hdr_size(self.block(ptr)) - DEBUG_WORDS - GUARD_WORDS) * 4;
+    }
+
+    pub unsafe fn stats(&self) -> HeapStats {
+        /* The biggest blocks are on the highest non-empty list */
+        let mut largest = 0;
+        if self.fl_bitmap != 0 {
+            let fl = (31 - self.fl_bitmap.leading_zeros()) as usize;
+            let sl = (31 - self.sl_bitmap[fl].leading_zeros()) as usize;
+            let mut hdr = self.free[fl][sl];
+            while !hdr.is_null() {
+                largest = core::cmp::max(largest, self.hdr_size(hdr));
+                hdr = *self.links(hdr).offset(0);
             }
+        }
 
-            /* Move on to the next node in the freelist.
-             * Adding one to the offsets moves the "pointer" 4 bytes.
-             * */
-            offset += ((node & cur_size_mask) as isize) + 1;
+        let size = (self.end as usize - self.start as usize) as u32;
+        let free = self.free_words * 4;
+        HeapStats {
+            size          : size,
+            used          : size - free - self.free_blocks * self.hdr_words * 4,
+            free          : free,
+            blocks        : self.free_blocks + self.allocs - self.frees,
+            free_blocks   : self.free_blocks,
+            largest_free  : largest * 4,
+            fragmentation : if free == 0 { 0 } else { 1000 - (largest * 4 * 1000) / free },
+            allocs        : self.allocs,
+            frees         : self.frees,
+            failures      : self.failures,
+            wide          : self.wide,
         }
+    }
+}
 
-        /* The size of the block that we've chosen to take from, divided by 4 bytes */
-        blocksize = node & cur_size_mask;
-        prevsize = node & prev_size_mask;
+#[cfg(feature = "heap_debug")]
+impl Heap {
+    /* Fill in the debug record and guards of a block just handed out for
+     * `size` bytes. */
+    unsafe fn rec(&self, hdr: *mut u32) -> *mut debug_record {
+        return self.payload(hdr) as *mut debug_record;
+    }
 
-        /* If there will only be 4 bytes left, just give it away */
-        if blocksize == (size + 1) {
-            size += 1;
+    unsafe fn record(&self, hdr: *mut u32, size: u32) {
+        let rec = self.rec(hdr);
+        (*rec).caller = self.caller;
+        (*rec).pid    = current_pid();
+        (*rec).size   = size;
+        (*rec).guard  = FRONT_GUARD;
+        *self.user(hdr).offset(((size + 3) / 4) as isize) = BACK_GUARD;
+    }
+
+    /* Fill a block that is about to be freed with POISON. */
+    unsafe fn poison(&self, hdr: *mut u32) {
+        let payload = self.payload(hdr);
+        for i in 0..self.hdr_size(hdr) {
+            *payload.offset(i as isize) = POISON;
+        }
+    }
+
+    /* Check both guards of a taken block, saying who allocated it if
+     * either has been overwritten. */
+    unsafe fn check_guards(&self, hdr: *mut u32) -> bool {
+        let rec  = self.rec(hdr);
+        let user = self.user(hdr);
+        let size = (*rec).size;
+
+        if (*rec).guard != FRONT_GUARD {
+            println!("HEAP: guard before {:p} overwritten ({:#x}), allocated by pid {} from {:#x}",
+                     user, (*rec).guard, (*rec).pid, (*rec).caller);
+            return false;
         }
-        
-        if(blocksize > size) {
-            /* Split the block that we've chosen to take from. Only do this if we're
-             * not consuming the entire block. Since this block is the block after
-             * the one that we're currently allocating, its "previous size" will
-             * be the size of the current allocation. It's size will be the
-             * size of the block minus what we're about to take from it to do
-             * the current allocation.
-             */
-            let nextnode: u32 = (((blocksize - size - 1) as u32) & cur_size_mask) |
-                                (((size as u32) << 16) & prev_size_mask);
-            start.offset(offset + (size as isize) + 1).write(nextnode);
+        if (size + 3) / 4 + DEBUG_WORDS + GUARD_WORDS > self.hdr_size(hdr) {
+            println!("HEAP: block {:p} records {} bytes but only holds {} words",
+                     user, size, self.hdr_size(hdr));
+            return false;
         }
+        if *user.offset(((size + 3) / 4) as isize) != BACK_GUARD {
+            println!("HEAP: write past the end of {:p} ({} bytes), allocated by pid {} from {:#x}",
+                     user, size, (*rec).pid, (*rec).caller);
+            return false;
+        }
+        return true;
+    }
 
-        /* We write this into memory to do the allocation */
-        node = taken_mask | 
-               ((size as u32) & cur_size_mask) |
-               ((prevsize as u32) & prev_size_mask);
-        start.offset(offset).write(node);
+    /* Walk every block and check that the headers agree with each other
+     * and with the free lists, and that no guard has been hit. */
+    unsafe fn validate(&self) -> bool {
+        let mut hdr  = self.start;
+        let mut prev = 0 as *mut u32;
+        let mut free_words  = 0;
+        let mut free_blocks = 0;
 
-        /* Return the pointer to the allocation */
-        start.offset(offset + 1) as *mut u32
+        while !hdr.is_null() {
+            let size   = self.hdr_size(hdr);
+            let expect = if prev.is_null() { 0 } else { self.hdr_size(prev) };
+
+            if self.hdr_prev(hdr) != expect {
+                println!("HEAP: block {:p} says the block before it is {} words, but it is {}",
+                         hdr, self.hdr_prev(hdr), expect);
+                return false;
+            }
+            if size < min_block_words || self.payload(hdr).offset(size as isize) > self.end {
+                println!("HEAP: block {:p} has a bad size of {} words", hdr, size);
+                return false;
+            }
+            if self.hdr_taken(hdr) {
+                if !self.check_guards(hdr) {
+                    return false;
+                }
+            } else {
+                if !prev.is_null() && !self.hdr_taken(prev) {
+                    println!("HEAP: free blocks {:p} and {:p} were not merged", prev, hdr);
+                    return false;
+                }
+                free_words  += size;
+                free_blocks += 1;
+            }
+
+            prev = hdr;
+            hdr  = self.next_phys(hdr);
+        }
+
+        if free_words != self.free_words || free_blocks != self.free_blocks {
+            println!("HEAP: free lists hold {} words in {} blocks, but the heap has {} words in {}",
+                     self.free_words, self.free_blocks, free_words, free_blocks);
+            return false;
+        }
+        return true;
+    }
+
+    /* Make sure `ptr` is something kfree may release: the start of a taken
+     * block, in a heap that is still consistent. */
+    unsafe fn check_free(&self, ptr: *mut u32) -> bool {
+        if !self.validate() {
+            return false;
+        }
+
+        let mut hdr = self.start;
+        while !hdr.is_null() {
+            let next = self.next_phys(hdr);
+            if ptr == self.user(hdr) {
+                if !self.hdr_taken(hdr) {
+                    println!("HEAP: double free of {:p} by pid {} from {:#x}",
+                             ptr, current_pid(), self.caller);
+                    return false;
+                }
+                return true;
+            }
+            if ptr > hdr && (next.is_null() || ptr < next) {
+                if self.hdr_taken(hdr) {
+                    let rec = self.rec(hdr);
+                    println!("HEAP: {:p} freed by pid {} from {:#x} points into the middle of {:p}, allocated by pid {} from {:#x}",
+                             ptr, current_pid(), self.caller, self.user(hdr), (*rec).pid, (*rec).caller);
+                } else {
+                    println!("HEAP: double free of {:p} by pid {} from {:#x}, it is inside free block {:p}",
+                             ptr, current_pid(), self.caller, hdr);
+                }
+                return false;
+            }
+            hdr = next;
+        }
+        return false;
+    }
+}
+
+/* The process on whose behalf the heap is being used, or -1 before the
+ * first one runs. */
+#[cfg(feature = "heap_debug")]
+unsafe fn current_pid() -> i32 {
+    if sched.current.is_null() {
+        return -1;
+    }
+    return (*sched.current).pid;
+}
+
+/* Remember where the kmalloc or kfree being served was called from. Must be
+ * inlined into a function that is not itself inlined, before it makes any
+ * calls, so that ra still holds that function's return address. */
+#[inline(always)]
+unsafe fn note_caller() {
+    #[cfg(feature = "heap_debug")]
+    {
+        let mut ra: usize = 0;
+        asm!("mv $0, ra" : "=r"(ra) ::: "volatile");
+        KHEAP.caller = ra;
+    }
+}
+
+/* Initialize the heap by simply creating a single free node at the beginning of the heap,
+ * which is at __heap_start, and hand the second half of the region to user memory.
+ */
+pub fn heap_init() -> () {
+    unsafe {
+        let ptr: *mut u32 = &mut __heap_start as *mut u32; /* Pointer to the start of the heap */
+        let end: *mut u32 = &mut __heap_end as *mut u32;
+        let split = ((ptr as usize + (end as usize - ptr as usize) / 2) & !(UPAGE_SIZE as usize - 1)) as *mut u32;
+        KHEAP.init(ptr, split);
+
+        println!("Initializing heap at {:p}, size: {:p}, ends at {:p}, {} headers",
+                 ptr, &__heap_size, KHEAP.end, if KHEAP.wide { "wide" } else { "packed" });
+
+        user_init(split, end);
+    }
+}
+
+/* Returns either:
+ *   1. A pointer to a 4-byte-aligned region of memory at least as large as you requested.
+ *   2. A NULL pointer, if the heap has no room.
+ */
+#[cfg_attr(feature = "heap_debug", inline(never))]
+pub fn kmalloc(size: u32) -> *mut u32 {
+    unsafe {
+        note_caller();
+        return KHEAP.alloc(size);
+    }
+}
+
+/* Frees the pointer that you give it. */
+#[cfg_attr(feature = "heap_debug", inline(never))]
+pub fn kfree(ptr: *mut u32) -> () {
+    unsafe {
+        note_caller();
+        KHEAP.free(ptr);
+    }
+}
+
+/* Like kmalloc, but the block starts on a multiple of `align`, which must
+ * be a power of two. Any space skipped to get there is split off as a free
+ * block of its own, so it is not lost.
+ * Returns NULL if there is no room or `align` is not a power of two.
+ */
+#[cfg_attr(feature = "heap_debug", inline(never))]
+pub fn kmalloc_aligned(size: u32, align: u32) -> *mut u32 {
+    unsafe {
+        note_caller();
+        return KHEAP.alloc_aligned(size, align);
+    }
+}
+
+/* Resize the allocation at `ptr` to `new_size` bytes, keeping its contents.
+ * Grows in place when the next block is free and big enough, otherwise
+ * moves to a new block; either way the block only keeps word alignment
+ * once it has moved. Returns the (possibly new) pointer, or NULL if there
+ * is no room, in which case `ptr` is left as it was.
+ * A NULL `ptr` is the same as kmalloc, and a `new_size` of zero frees it.
+ */
+#[cfg_attr(feature = "heap_debug", inline(never))]
+pub fn krealloc(ptr: *mut u32, new_size: u32) -> *mut u32 {
+    unsafe {
+        note_caller();
+        return KHEAP.realloc(ptr, new_size);
+    }
+}
+
+/* The number of bytes usable at `ptr`, which must have come from kmalloc
+ * and not been freed. This is what the block really costs the heap, less
+ * its header. */
+pub fn ksize(ptr: *mut u32) -> u32 {
+    unsafe {
+        return KHEAP.usable(ptr);
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    unsafe {
+        return KHEAP.stats();
     }
 }
 
 pub fn heap_print(max_offset: isize) -> () {
     unsafe {
-        let start: *mut u32 = &mut __heap_start as *mut u32;
+        let start: *mut u32 = KHEAP.start;
         let mut offset: isize = 0;
-        let end: *mut u32 = &mut __heap_end as *mut u32;
+        let end: *mut u32 = KHEAP.end;
         let mut node: u32;
 
         /* Print the whole heap. */
@@ -164,75 +896,91 @@ pub fn heap_print(max_offset: isize) -> () {
             println!("{:032b}", node);
             offset += 1;
         }
+        let stats = heap_stats();
+        println!("free: {} bytes in {} blocks, largest {}, fragmentation {}/1000",
+                 stats.free, stats.free_blocks, stats.largest_free, stats.fragmentation);
         println!("===== HEAP =====");
     }
 }
 
-/* Frees the pointer that you give it. */
-pub fn kfree(arg_ptr: *mut u32) -> () {
+/* Print every block of the kernel heap in address order: its offset from
+ * the start of the heap, its size (header included), whether it is taken
+ * and, where that can be worked out, who for. */
+pub fn heap_dump() {
     unsafe {
-        let start: *mut u32;
-        let end: *mut u32;
-        let mut node: u32;
-        let mut prevnode: u32;
-        let nextnode: u32;
-        let mut offset: isize;
-        let mut ptr: *mut u32;
-        let mut size: u32;
-        let prevsize: u32;
-
-        ptr = arg_ptr;
-        start = &mut __heap_start as *mut u32;
-        end = &mut __heap_end as *mut u32;
-
-        /* Sanity checks */
-        if (ptr < (start as *mut u32)) || (ptr >= (end as *mut u32)) {
-            println!("ERROR: Pointer was invalid: {:p}", ptr);
-            return;
+        println!("===== KERNEL HEAP =====");
+        println!("  offset   bytes  state  owner");
+        let mut hdr = KHEAP.start;
+        while !hdr.is_null() {
+            let bytes  = (KHEAP.hdr_size(hdr) + KHEAP.hdr_words) * 4;
+            let offset = hdr as usize - KHEAP.start as usize;
+            if !KHEAP.hdr_taken(hdr) {
+                println!("{:>8x} {:>7}  free", offset, bytes);
+            } else {
+                match sched.owner_of(KHEAP.user(hdr) as u32, bytes) {
+                    Some((pid, what)) => println!("{:>8x} {:>7}  taken  {} of pid {}", offset, bytes, what, pid),
+                    None              => KHEAP.dump_unknown(hdr, offset, bytes),
+                }
+            }
+            hdr = KHEAP.next_phys(hdr);
         }
+        let stats = KHEAP.stats();
+        println!("{} of {} bytes free in {} of {} blocks, largest {}, fragmentation {}/1000",
+                 stats.free, stats.size, stats.free_blocks, stats.blocks,
+                 stats.largest_free, stats.fragmentation);
+        println!("===== KERNEL HEAP =====");
+    }
+}
 
-        /* Grab the node in the freelist that's going to be freed.
-         * Go ahead and free it by usetting the "taken" bit.
-         */
-        ptr = ptr.offset(-1);
-        node = ptr.read();
-        node &= !taken_mask;
-        ptr.write(node);
+impl Heap {
+    /* A taken block no process claims. With heap_debug we at least know
+     * who allocated it. */
+    unsafe fn dump_unknown(&self, hdr: *mut u32, offset: usize, bytes: u32) {
+        #[cfg(feature = "heap_debug")]
+        {
+            let rec = self.rec(hdr);
+            println!("{:>8x} {:>7}  taken  kernel, {} bytes for pid {} from {:#x}",
+                     offset, bytes, (*rec).size, (*rec).pid, (*rec).caller);
+        }
+        #[cfg(not(feature = "heap_debug"))]
+        {
+            println!("{:>8x} {:>7}  taken  kernel", offset, bytes);
+        }
+    }
+}
 
-        /* Save the information in the node */
-        size = node & cur_size_mask;
-        prevsize = (node & prev_size_mask) >> 16;
+/* The kernel heap behind Box, Vec and the rest of the alloc crate. */
+pub struct KernelAllocator;
 
-        /* Look down to see if we need to merge the block below. */
-        offset = (size + 1) as isize;
-        if ptr.offset(offset) < end {
-            nextnode = ptr.offset(offset).read();
-            if (nextnode & taken_mask) == 0 {
-                /* If the next node isn't taken, merge. Use the stored size
-                 * since we're clearing it out in memory.
-                 */
-                node &= !cur_size_mask;
-                size = (nextnode & cur_size_mask) + size + 1;
-                node |= size & cur_size_mask;
-                ptr.write(node);
-            }
+unsafe impl GlobalAlloc for KernelAllocator {
+    /* Sizes are kept in 32 bits, so anything bigger can't be had */
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > core::u32::MAX as usize || layout.align() > core::u32::MAX as usize {
+            return 0 as *mut u8;
         }
+        return kmalloc_aligned(layout.size() as u32, layout.align() as u32) as *mut u8;
+    }
 
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        kfree(ptr as *mut u32);
+    }
 
-        /* Look up to see if we need to merge the block above. */
-        offset = (prevsize + 1) as isize;
-        if prevsize != 0 {
-            prevnode = ptr.offset(offset.wrapping_neg()).read();
-            // println!("Prevsize: {}", prevsize);
-            // println!("Offset: {}", offset);
-            // println!("Offset: {}", offset.wrapping_neg());
-            // println!("Prevnode: {:032b}", prevnode);
-            if (prevnode & taken_mask) == 0 {
-                /* If the previous node isn't taken, merge. */
-                prevnode &= !cur_size_mask;
-                prevnode |= (prevsize + size + 1) & cur_size_mask;
-                ptr.offset(offset.wrapping_neg()).write(prevnode);
+    /* krealloc gives up any alignment past a word when it moves a block */
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > core::u32::MAX as usize {
+            return 0 as *mut u8;
+        }
+        if layout.align() > 4 {
+            let new = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+            if !new.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new, core::cmp::min(layout.size(), new_size));
+                self.dealloc(ptr, layout);
             }
+            return new;
         }
+        return krealloc(ptr as *mut u32, new_size as u32) as *mut u8;
     }
 }
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
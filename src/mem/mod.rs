@@ -1,2 +1,4 @@
 pub mod heap;
 pub mod pmp;
+pub mod slab;
+pub mod user;
@@ -0,0 +1,225 @@
+/* Object caches.
+ * The kernel makes and destroys a handful of fixed-size objects all the
+ * time (PCBs, rbtrees and their nodes). Rather than send each of them through kmalloc,
+ * an ObjectCache carves objects of one type out of larger blocks ("slabs")
+ * taken from the heap, and keeps the free ones on a list. Handing one out
+ * or taking it back is a couple of pointer updates, and since objects of a
+ * type are packed together they don't chop the small heap up between
+ * bigger allocations.
+ * Every object slot starts with a pointer back to its slab, so freeing
+ * never has to search. A slab that empties goes back to the heap if the
+ * cache has another partial slab to hand objects out from, or has nothing
+ * out at all; so a cache pins at most one empty slab, and only while its
+ * objects are in use.
+ */
+use crate::console as console;
+use core::fmt::Write;
+use core::mem::{ size_of, align_of };
+use crate::mem::heap::{ kmalloc_aligned, kfree };
+
+/* At the start of every slab, followed by its object slots. */
+#[repr(C)]
+struct slab {
+    next  : *mut slab,
+    prev  : *mut slab,
+    /* Free slots in this slab, linked through their first object word */
+    free  : *mut *mut u8,
+    inuse : u32,
+}
+
+/* A snapshot of how a cache is being used. */
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub name      : &'static str,
+    /* Bytes per object, and per slot including the slab pointer */
+    pub obj_size  : u32,
+    pub slot_size : u32,
+    pub slabs     : u32,
+    pub in_use    : u32,
+    pub free      : u32,
+    pub allocs    : u32,
+    pub frees     : u32,
+    pub failures  : u32,
+}
+
+pub struct ObjectCache<T> {
+    name     : &'static str,
+    per_slab : u32,
+    /* Run on every object before it is handed out */
+    ctor     : Option<unsafe fn(*mut T)>,
+    /* Slabs with at least one free slot, and those with none */
+    partial  : *mut slab,
+    full     : *mut slab,
+    slabs    : u32,
+    in_use   : u32,
+    allocs   : u32,
+    frees    : u32,
+    failures : u32,
+}
+
+impl<T> ObjectCache<T> {
+    /* A cache that takes `per_slab` objects' worth of heap at a time. No
+     * memory is taken until the first alloc. */
+    pub const fn new(name: &'static str, per_slab: u32, ctor: Option<unsafe fn(*mut T)>) -> Self {
+        ObjectCache {
+            name     : name,
+            per_slab : per_slab,
+            ctor     : ctor,
+            partial  : 0 as *mut slab,
+            full     : 0 as *mut slab,
+            slabs    : 0,
+            in_use   : 0,
+            allocs   : 0,
+            frees    : 0,
+            failures : 0,
+        }
+    }
+
+    /* Objects, slots and slabs all keep T's alignment, and at least a
+     * pointer's so the free links and slab pointers line up. */
+    fn align() -> usize {
+        return core::cmp::max(align_of::<T>(), size_of::<usize>());
+    }
+
+    fn round(n: usize) -> usize {
+        let align = ObjectCache::<T>::align();
+        return (n + align - 1) & !(align - 1);
+    }
+
+    /* Where a slot's object starts */
+    fn obj_offset() -> usize {
+        return ObjectCache::<T>::round(size_of::<*mut slab>());
+    }
+
+    fn slot_size() -> usize {
+        let obj = core::cmp::max(size_of::<T>(), size_of::<usize>());
+        return ObjectCache::<T>::round(ObjectCache::<T>::obj_offset() + obj);
+    }
+
+    fn slots_offset() -> usize {
+        return ObjectCache::<T>::round(size_of::<slab>());
+    }
+
+    unsafe fn push(list: *mut *mut slab, s: *mut slab) {
+        (*s).prev = 0 as *mut slab;
+        (*s).next = *list;
+        if !(*list).is_null() {
+            (**list).prev = s;
+        }
+        *list = s;
+    }
+
+    unsafe fn unlink(list: *mut *mut slab, s: *mut slab) {
+        if (*s).prev.is_null() {
+            *list = (*s).next;
+        } else {
+            (*(*s).prev).next = (*s).next;
+        }
+        if !(*s).next.is_null() {
+            (*(*s).next).prev = (*s).prev;
+        }
+    }
+
+    /* Take a new slab from the heap and put all of its slots on its free
+     * list. Returns false if the heap has no room. */
+    unsafe fn grow(&mut self) -> bool {
+        let slot  = ObjectCache::<T>::slot_size();
+        let size  = ObjectCache::<T>::slots_offset() + slot * self.per_slab as usize;
+        let s     = kmalloc_aligned(size as u32, ObjectCache::<T>::align() as u32) as *mut slab;
+        if s.is_null() {
+            return false;
+        }
+
+        (*s).free  = 0 as *mut *mut u8;
+        (*s).inuse = 0;
+        let slots = (s as *mut u8).offset(ObjectCache::<T>::slots_offset() as isize);
+        for i in (0..self.per_slab as usize).rev() {
+            let slot = slots.offset((i * slot) as isize);
+            *(slot as *mut *mut slab) = s;
+
+            let obj = slot.offset(ObjectCache::<T>::obj_offset() as isize) as *mut *mut u8;
+            *obj = (*s).free as *mut u8;
+            (*s).free = obj;
+        }
+
+        ObjectCache::<T>::push(&mut self.partial, s);
+        self.slabs += 1;
+        return true;
+    }
+
+    /* Returns an object that has been through the constructor, if the cache
+     * has one, and is otherwise uninitialized; or NULL if the heap is full. */
+    pub unsafe fn alloc(&mut self) -> *mut T {
+        if self.partial.is_null() && !self.grow() {
+            self.failures += 1;
+            return 0 as *mut T;
+        }
+
+        let s   = self.partial;
+        let obj = (*s).free;
+        (*s).free   = *obj as *mut *mut u8;
+        (*s).inuse += 1;
+        if (*s).free.is_null() {
+            ObjectCache::<T>::unlink(&mut self.partial, s);
+            ObjectCache::<T>::push(&mut self.full, s);
+        }
+
+        self.in_use += 1;
+        self.allocs += 1;
+        let obj = obj as *mut T;
+        if let Some(ctor) = self.ctor {
+            ctor(obj);
+        }
+        return obj;
+    }
+
+    /* Give back an object from alloc. It is not dropped. */
+    pub unsafe fn free(&mut self, obj: *mut T) {
+        if obj.is_null() {
+            return;
+        }
+        let slot = (obj as *mut u8).offset(-(ObjectCache::<T>::obj_offset() as isize));
+        let s    = *(slot as *mut *mut slab);
+
+        if (*s).free.is_null() {
+            ObjectCache::<T>::unlink(&mut self.full, s);
+            ObjectCache::<T>::push(&mut self.partial, s);
+        }
+        let obj = obj as *mut *mut u8;
+        *obj = (*s).free as *mut u8;
+        (*s).free   = obj;
+        (*s).inuse -= 1;
+
+        self.in_use -= 1;
+        self.frees  += 1;
+
+        /* Keep an empty slab only while it is the one place left to
+         * allocate from and the cache is still in use */
+        if (*s).inuse == 0 && (!(*s).next.is_null() || !(*s).prev.is_null() || self.in_use == 0) {
+            ObjectCache::<T>::unlink(&mut self.partial, s);
+            kfree(s as *mut u32);
+            self.slabs -= 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name      : self.name,
+            obj_size  : size_of::<T>() as u32,
+            slot_size : ObjectCache::<T>::slot_size() as u32,
+            slabs     : self.slabs,
+            in_use    : self.in_use,
+            free      : self.slabs * self.per_slab - self.in_use,
+            allocs    : self.allocs,
+            frees     : self.frees,
+            failures  : self.failures,
+        }
+    }
+
+    pub fn print(&self) {
+        let stats = self.stats();
+        println!("{}: {} of {} in use ({} bytes each) in {} slabs, {} allocs, {} frees, {} failed",
+                 stats.name, stats.in_use, stats.in_use + stats.free, stats.slot_size,
+                 stats.slabs, stats.allocs, stats.frees, stats.failures);
+    }
+}
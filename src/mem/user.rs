@@ -0,0 +1,258 @@
+/* User memory.
+ * The top part of the heap region is kept apart from the kernel heap and
+ * holds only what processes themselves read and write: their stacks, loaded
+ * program images and BRK arenas. It is handed out in whole pages, and the
+ * bitmap saying which pages are taken lives in the kernel heap, so there is
+ * no kernel data in here at all. A process writing past the end of its
+ * buffer can clobber another process's memory, but never the kernel's.
+ * Finding pages is a walk over the bitmap. There are only a few dozen pages
+ * on a 16K machine, and processes come and go far less often than they
+ * allocate.
+ */
+use crate::console as console;
+use core::fmt::Write;
+use crate::mem::heap::kmalloc;
+use crate::scheduler::sched;
+
+pub const UPAGE_SIZE: u32 = 256;
+
+/* A snapshot of user memory. Sizes are in bytes. */
+#[derive(Clone, Copy, Debug)]
+pub struct UserStats {
+    pub size         : u32,
+    pub free         : u32,
+    pub largest_free : u32,
+}
+
+struct UserMem {
+    start: u32,
+    pages: u32,
+    free_pages: u32,
+    /* Bit p of word p / 32 is set if page p is taken */
+    map: *mut u32,
+}
+
+static mut UMEM: UserMem = UserMem {
+    start: 0,
+    pages: 0,
+    free_pages: 0,
+    map: 0 as *mut u32,
+};
+
+/* How many pages `bytes` takes up */
+fn pages(bytes: u32) -> u32 {
+    return (bytes + UPAGE_SIZE - 1) / UPAGE_SIZE;
+}
+
+/* What `bytes` really costs once rounded up to whole pages */
+pub fn upage_round(bytes: u32) -> u32 {
+    return pages(bytes) * UPAGE_SIZE;
+}
+
+impl UserMem {
+    unsafe fn taken(&self, page: u32) -> bool {
+        return (*self.map.offset((page / 32) as isize) & (1 << (page % 32))) != 0;
+    }
+
+    unsafe fn mark(&mut self, first: u32, n: u32, taken: bool) {
+        for page in first..first + n {
+            let word = self.map.offset((page / 32) as isize);
+            if taken {
+                *word |= 1 << (page % 32);
+            } else {
+                *word &= !(1 << (page % 32));
+            }
+        }
+        if taken {
+            self.free_pages -= n;
+        } else {
+            self.free_pages += n;
+        }
+    }
+
+    /* Call `f(first, len)` for every run of free pages */
+    unsafe fn free_runs<F: FnMut(u32, u32)>(&self, mut f: F) {
+        let mut page = 0;
+        while page < self.pages {
+            if self.taken(page) {
+                page += 1;
+                continue;
+            }
+            let first = page;
+            while page < self.pages && !self.taken(page) {
+                page += 1;
+            }
+            f(first, page - first);
+        }
+    }
+
+    /* Take `n` pages from the smallest free run that holds them, or from
+     * the start of the largest one if `roomy`. Returns the first page, or
+     * None. */
+    unsafe fn take(&mut self, n: u32, roomy: bool) -> Option<u32> {
+        let mut best: Option<(u32, u32)> = None;
+        self.free_runs(|first, len| {
+            if len < n {
+                return;
+            }
+            best = match best {
+                Some((_, best_len)) if (roomy && best_len >= len) || (!roomy && best_len <= len) => best,
+                _ => Some((first, len)),
+            };
+        });
+
+        let (first, _) = best?;
+        self.mark(first, n, true);
+        return Some(first);
+    }
+
+    fn page_of(&self, ptr: *mut u32) -> u32 {
+        return (ptr as u32 - self.start) / UPAGE_SIZE;
+    }
+
+    fn addr_of(&self, page: u32) -> *mut u32 {
+        return (self.start + page * UPAGE_SIZE) as *mut u32;
+    }
+}
+
+/* Manage [start, end) as user memory. The page bitmap comes from the kernel
+ * heap, so that must be set up first. */
+pub fn user_init(start: *mut u32, end: *mut u32) {
+    unsafe {
+        let start = (start as u32 + UPAGE_SIZE - 1) & !(UPAGE_SIZE - 1);
+        let n     = if end as u32 > start { (end as u32 - start) / UPAGE_SIZE } else { 0 };
+
+        let map = kmalloc(core::cmp::max((n + 31) / 32 * 4, 4));
+        if map.is_null() {
+            println!("could not allocate the user memory map!");
+            return;
+        }
+        for i in 0..(n + 31) / 32 {
+            *map.offset(i as isize) = 0;
+        }
+
+        UMEM = UserMem { start: start, pages: n, free_pages: n, map: map };
+        println!("Initializing user memory at {:#x}, {} pages of {} bytes", start, n, UPAGE_SIZE);
+    }
+}
+
+/* Returns a page-aligned block of at least `bytes`, or NULL if there is no
+ * run of free pages that big. Blocks are packed into the smallest gap that
+ * fits them. */
+pub fn ualloc(bytes: u32) -> *mut u32 {
+    unsafe {
+        if bytes == 0 {
+            return 0 as *mut u32;
+        }
+        return match UMEM.take(pages(bytes), false) {
+            Some(page) => UMEM.addr_of(page),
+            None       => 0 as *mut u32,
+        };
+    }
+}
+
+/* Like ualloc, but starts the block at the beginning of the biggest gap,
+ * leaving as many free pages after it as there can be, for a block that is
+ * going to grow with uresize. */
+pub fn ualloc_arena(bytes: u32) -> *mut u32 {
+    unsafe {
+        if bytes == 0 {
+            return 0 as *mut u32;
+        }
+        return match UMEM.take(pages(bytes), true) {
+            Some(page) => UMEM.addr_of(page),
+            None       => 0 as *mut u32,
+        };
+    }
+}
+
+/* Give back a block of `bytes` from ualloc. */
+pub fn ufree(ptr: *mut u32, bytes: u32) {
+    unsafe {
+        if ptr.is_null() || bytes == 0 {
+            return;
+        }
+        if (ptr as u32) < UMEM.start || UMEM.page_of(ptr) + pages(bytes) > UMEM.pages {
+            println!("ERROR: not user memory: {:p}", ptr);
+            return;
+        }
+        let page = UMEM.page_of(ptr);
+        UMEM.mark(page, pages(bytes), false);
+    }
+}
+
+/* Resize a block from ualloc from `old` bytes to `new` without moving it.
+ * Shrinking always works; growing needs the pages after the block to be
+ * free. Returns false, leaving the block as it was, if they are not. */
+pub fn uresize(ptr: *mut u32, old: u32, new: u32) -> bool {
+    unsafe {
+        let first = UMEM.page_of(ptr);
+        let have  = pages(old);
+        let want  = pages(new);
+
+        if want < have {
+            UMEM.mark(first + want, have - want, false);
+            return true;
+        }
+        if first + want > UMEM.pages {
+            return false;
+        }
+        for page in first + have..first + want {
+            if UMEM.taken(page) {
+                return false;
+            }
+        }
+        UMEM.mark(first + have, want - have, true);
+        return true;
+    }
+}
+
+pub fn user_stats() -> UserStats {
+    unsafe {
+        let mut largest = 0;
+        UMEM.free_runs(|_, len| {
+            largest = core::cmp::max(largest, len);
+        });
+        UserStats {
+            size         : UMEM.pages * UPAGE_SIZE,
+            free         : UMEM.free_pages * UPAGE_SIZE,
+            largest_free : largest * UPAGE_SIZE,
+        }
+    }
+}
+
+/* Print user memory in address order: each run of free pages, and each run
+ * of taken pages belonging to the same thing, with its offset from the
+ * start of user memory, size and owner. */
+pub fn user_dump() {
+    unsafe {
+        println!("===== USER MEMORY =====");
+        println!("  offset   bytes  owner");
+        let mut page = 0;
+        while page < UMEM.pages {
+            let first = page;
+            if !UMEM.taken(page) {
+                while page < UMEM.pages && !UMEM.taken(page) {
+                    page += 1;
+                }
+                println!("{:>8x} {:>7}  free", first * UPAGE_SIZE, (page - first) * UPAGE_SIZE);
+                continue;
+            }
+
+            let owner = sched.owner_of(UMEM.addr_of(page) as u32, UPAGE_SIZE);
+            while page < UMEM.pages && UMEM.taken(page)
+                  && sched.owner_of(UMEM.addr_of(page) as u32, UPAGE_SIZE) == owner {
+                page += 1;
+            }
+            match owner {
+                Some((pid, what)) => println!("{:>8x} {:>7}  {} of pid {}", first * UPAGE_SIZE,
+                                              (page - first) * UPAGE_SIZE, what, pid),
+                None              => println!("{:>8x} {:>7}  unknown", first * UPAGE_SIZE,
+                                              (page - first) * UPAGE_SIZE),
+            }
+        }
+        let stats = user_stats();
+        println!("{} of {} bytes free, largest {}", stats.free, stats.size, stats.largest_free);
+        println!("===== USER MEMORY =====");
+    }
+}
@@ -0,0 +1,53 @@
+/* cfs.rs
+ *
+ * The original policy: processes sit in an rbtree keyed on vruntime and the
+ * leftmost one runs next. Each tick adds the process's QM to its vruntime,
+ * so a larger QM means a smaller share of the CPU.
+ */
+
+use crate::scheduler::PCB;
+use crate::scheduler::policy::{*};
+use crate::utils::rbtree::rbtree;
+
+pub struct CFS {
+    tree: *mut rbtree<u32, *mut PCB>,
+}
+
+impl CFS {
+    pub const fn new() -> Self {
+        CFS { tree: core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB> }
+    }
+}
+
+impl SchedPolicy for CFS {
+    fn init(&mut self) {
+        self.tree = new_tree();
+    }
+
+    unsafe fn admit(&mut self, pcb: *mut PCB) -> bool {
+        (*pcb).vruntime = (*self.tree).len as u32;
+        return self.enqueue(pcb);
+    }
+
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool {
+        return add_to_tree(self.tree, (*pcb).vruntime, pcb);
+    }
+
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool {
+        return remove_from_tree(self.tree, pcb);
+    }
+
+    unsafe fn pick_next(&mut self) -> *mut PCB {
+        return tree_pop_first(self.tree);
+    }
+
+    unsafe fn tick(&mut self, pcb: *mut PCB) {
+        (*pcb).vruntime += (*pcb).QM;
+    }
+
+    unsafe fn yield_now(&mut self, _pcb: *mut PCB) {}
+
+    fn len(&self) -> usize {
+        unsafe { (*self.tree).len }
+    }
+}
@@ -0,0 +1,53 @@
+/* fpu.rs
+ *
+ * Floating-point context for F/D-capable harts, built with the `fpu`
+ * feature. FP registers are switched lazily: a process that hasn't touched
+ * them since it was switched in runs with mstatus.FS off, so its first FP
+ * instruction traps and the registers are loaded then. The previous owner's
+ * registers are only written back if FS says they were dirtied, so
+ * integer-only processes never pay for a save or a restore.
+ */
+
+extern "C" {
+    /* asm/fp.S */
+    fn fp_save(state: *mut fp_state);
+    fn fp_restore(state: *const fp_state);
+}
+
+/* mstatus.FS, bits 13-14 */
+const FS_SHIFT : u32 = 13;
+const FS_MASK  : u32 = 0x3 << FS_SHIFT;
+pub const FS_OFF   : u32 = 0;
+pub const FS_CLEAN : u32 = 2;
+pub const FS_DIRTY : u32 = 3;
+
+/* Laid out the way asm/fp.S expects it */
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct fp_state {
+    pub f    : [u64; 32],
+    pub fcsr : u32,
+}
+
+pub fn fs() -> u32 {
+    let mut mstatus: u32 = 0;
+    unsafe {
+        asm!("csrr $0, mstatus" : "=r"(mstatus) ::: "volatile");
+    }
+    return (mstatus & FS_MASK) >> FS_SHIFT;
+}
+
+pub fn set_fs(state: u32) {
+    unsafe {
+        asm!("csrc mstatus, $0" :: "r"(FS_MASK) :: "volatile");
+        asm!("csrs mstatus, $0" :: "r"(state << FS_SHIFT) :: "volatile");
+    }
+}
+
+pub unsafe fn save(state: *mut fp_state) {
+    fp_save(state);
+}
+
+pub unsafe fn restore(state: *const fp_state) {
+    fp_restore(state);
+}
@@ -0,0 +1,103 @@
+/* lottery.rs
+ *
+ * Lottery scheduling. Every process holds tickets_of() tickets, fewer the
+ * larger its QM; each pick draws a random ticket and the process holding
+ * it runs. The random numbers come from a xorshift generator seeded from
+ * mtime. The tree is only there to hold the processes, so its sequence
+ * keys may wrap.
+ */
+
+use crate::scheduler::PCB;
+use crate::scheduler::policy::{*};
+use crate::utils::rbtree::{ rbtree, rbtree_node };
+use crate::machine_info::{*};
+
+pub struct Lottery {
+    tree   : *mut rbtree<u32, *mut PCB>,
+    seq    : u32,
+    tickets: u32,
+    rng    : u32,
+}
+
+impl Lottery {
+    pub const fn new() -> Self {
+        Lottery {
+            tree   : core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            seq    : 0,
+            tickets: 0,
+            rng    : 0,
+        }
+    }
+
+    fn random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        return x;
+    }
+
+    /* Walk the tree in order until `winner` falls inside a process's tickets. */
+    unsafe fn _find_winner(node : *mut rbtree_node<u32, *mut PCB>, winner : &mut u32) -> *mut rbtree_node<u32, *mut PCB> {
+        if node.is_null() { return node; }
+
+        let l = Lottery::_find_winner((*node).children[0], winner);
+        if !l.is_null() { return l; }
+
+        let t = tickets_of((*node).val);
+        if *winner < t { return node; }
+        *winner -= t;
+
+        return Lottery::_find_winner((*node).children[1], winner);
+    }
+}
+
+impl SchedPolicy for Lottery {
+    fn init(&mut self) {
+        self.tree = new_tree();
+        self.rng  = *get_clint_register(ClintRegister :: MTIMELO) | 1;
+    }
+
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool {
+        if !add_to_tree(self.tree, self.seq, pcb) {
+            return false;
+        }
+        self.seq      = self.seq.wrapping_add(1);
+        self.tickets += tickets_of(pcb);
+        return true;
+    }
+
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool {
+        if remove_from_tree(self.tree, pcb) {
+            self.tickets -= tickets_of(pcb);
+            return true;
+        }
+        return false;
+    }
+
+    unsafe fn pick_next(&mut self) -> *mut PCB {
+        if self.tickets == 0 {
+            return core::ptr::null::<PCB>() as *mut PCB;
+        }
+
+        let mut winner = self.random() % self.tickets;
+        let node = Lottery::_find_winner((*self.tree).root, &mut winner);
+        if node.is_null() {
+            return core::ptr::null::<PCB>() as *mut PCB;
+        }
+
+        let pcb = (*node).val;
+        (*self.tree).delete((*node).key);
+        self.tickets -= tickets_of(pcb);
+        return pcb;
+    }
+
+    unsafe fn tick(&mut self, _pcb: *mut PCB) {}
+
+    unsafe fn yield_now(&mut self, _pcb: *mut PCB) {}
+
+    fn len(&self) -> usize {
+        unsafe { (*self.tree).len }
+    }
+}
@@ -1,35 +1,240 @@
+pub mod policy;
+pub mod cfs;
+pub mod rr;
+pub mod lottery;
+pub mod stride;
+#[cfg(feature = "fpu")]
+pub mod fpu;
+
 use crate::console;
 use crate::machine_info::{*};
 use crate::utils::rbtree::{ rbtree, rbtree_node };
 use core::fmt::Write;
 use core::ptr::null;
 use crate::mem::heap::{*};
-use crate::console::{print_c_str};
+use crate::syscalls::{ WAIT_ANY, proc_args };
+use crate::loader;
+use crate::scheduler::policy::{ SchedPolicy, Policy, new_tree, add_to_tree, remove_from_tree };
+use crate::mem::slab::ObjectCache;
+use crate::mem::user::{ UPAGE_SIZE, ualloc, ualloc_arena, ufree, uresize, upage_round, user_stats };
+#[cfg(feature = "heap_debug")]
+use crate::mem::user::user_dump;
+use alloc::boxed::Box;
 
 extern "C" {
-    static mut GLOBAL_CTX: [u32; 32];
+    static mut BOOT_FRAME: [u32; 33];
+    fn swtch(old: *mut kcontext, new: *const kcontext);
+    fn _proc_entry();
 }
 
 pub static mut sched:scheduler = scheduler::new();
 
+/* PCBs and the process table's nodes come from caches of their own */
+pub static mut PCB_CACHE: ObjectCache<PCB> = ObjectCache::new("pcb", 2, Some(pcb_ctor));
+pub static mut PROC_NODES: ObjectCache<rbtree_node<i32, *mut PCB>> = ObjectCache::new("proc nodes", 8, None);
+
+/* Every PCB starts out zeroed */
+unsafe fn pcb_ctor(pcb: *mut PCB) {
+    core::ptr::write(pcb, core::mem::zeroed());
+}
+
+/* The idle task only ever sleeps, so it gets a tiny stack. It has no PID
+ * and never appears in the process table or the run queues. */
+const IDLE_STACK_SIZE : u32 = 256;
+const IDLE_PID        : i32 = -1;
+
+/* Orphans are handed to init, which is always the first process */
+pub const INIT_PID    : i32 = 0;
+/* Exit status recorded for processes removed with KILL */
+pub const EXIT_KILLED : i32 = -1;
+/* Exit status of a process killed because the heap ran out */
+pub const EXIT_OOM    : i32 = -2;
+/* Names are copied into the PCB and always NUL terminated */
+pub const PROC_NAME_LEN : usize = 16;
+/* Every process, idle included, traps onto its own kernel stack */
+pub const KSTACK_SIZE : u32 = 1024;
+
+#[no_mangle]
+unsafe fn idle_loop() -> ! {
+    loop {
+        asm!("wfi");
+    }
+}
+
+/* Scheduling classes. Deadline processes are always picked ahead of
+ * fair (vruntime) processes. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedClass {
+    Fair,
+    Deadline,
+}
+
+/* Utilisation is tracked in fixed point; DL_UTIL_SCALE is 100% of the CPU. */
+pub const DL_UTIL_SCALE : u32 = 1024;
+
+/* Callee-saved registers of a process suspended inside the kernel, laid out
+ * the way asm/swtch.S expects them. */
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct kcontext {
+    pub ra : u32,
+    pub sp : u32,
+    pub s  : [u32; 12],
+}
+
+impl kcontext {
+    pub const fn new() -> Self {
+        kcontext { ra: 0, sp: 0, s: [0; 12] }
+    }
+}
+
+/* asm/trap.S saves registers straight into `context` and takes its kernel
+ * stack from `ksp`, through a pointer to `context` kept in mscratch. The two
+ * must stay first and in this order. */
+#[repr(C)]
 #[derive(Clone, Debug)]
 pub struct PCB {
     pub context            : [u32; 32],
+    pub ksp                :  u32,
+    /* f0-f31 and fcsr, only up to date while we don't own the FPU */
+    #[cfg(feature = "fpu")]
+    pub fp                 :  fpu::fp_state,
     pub pc                 :  u32,
     pub vruntime           :  u32,
+    /* CPU weight: a larger QM means a smaller share (see policy.rs) */
     pub QM                 :  u32,
     pub pid                :  i32,
     pub stack_size         :  u32,
     pub stack_pointer      :  u32,
-    pub name               :  *const char,
+    /* User memory holding a loaded program image or XIP data, or null */
+    pub image              :  *mut u32,
+    pub image_size         :  u32,
+    /* Kernel stack, and where we left off on it when switched away */
+    pub kstack             :  *mut u32,
+    pub kctx               :  kcontext,
+    /* Non-zero while blocked in sleep_on() until wakeup() on this channel */
+    pub wchan              :  u32,
+    pub name               :  [u8; PROC_NAME_LEN],
     pub kill               :  bool,
+    /* Status to exit with once `kill` takes effect */
+    pub kill_status        :  i32,
+    pub zombie             :  bool,
+    pub exit_status        :  i32,
     pub waitpid            :  i32,
+    /* Thread this one is blocked in THREAD_JOIN on, or -1 */
+    pub join_tid           :  i32,
+    /* Where to store the reaped child's status when WAITPID completes */
+    pub wait_status        :  u32,
+    /* Process tree: first child, and the next child of our parent */
+    pub parent             :  *mut PCB,
+    pub children           :  *mut PCB,
+    pub sibling            :  *mut PCB,
+    /* Thread group. The leader is the process itself (tgid == pid, `leader`
+     * null) and keeps every other thread on its `threads` list. Threads share
+     * the leader's image and heap allocations but have their own stacks. */
+    pub tgid               :  i32,
+    pub leader             :  *mut PCB,
+    pub threads            :  *mut PCB,
+    pub thread_next        :  *mut PCB,
+    /* Links the processes pick_runnable has set aside, so that picking
+     * never has to allocate */
+    pub wait_next          :  *mut PCB,
+    /* The group's arena in user memory, what has been claimed for it in
+     * bytes, and the break BRK has set within it. Kept on the leader. */
+    pub arena              :  *mut u32,
+    pub arena_size         :  u32,
+    pub brk                :  u32,
+    /* Most the group and its child processes may hold, counted by
+     * mem_usage, in bytes, or 0 for no limit. Kept on the leader. */
+    pub mem_limit          :  u32,
+    /* CPU accounting, all in mtime ticks */
+    pub utime              :  u64,
+    pub ktime              :  u64,
+    pub slice_ktime        :  u64,
+    pub last_run           :  u64,
+    pub wake_stamp         :  u64,
+    pub wake_latency_total :  u64,
+    pub wake_latency_max   :  u64,
+    pub wakeups            :  u32,
+    pub nvcsw              :  u32,
+    pub nivcsw             :  u32,
     pub sleep              :  i16,
+    pub class              :  SchedClass,
+    /* Deadline parameters, all in scheduler ticks */
+    pub dl_runtime         :  u32,
+    pub dl_period          :  u32,
+    pub dl_deadline        :  u32,
+    pub dl_abs_deadline    :  u32,
+    pub dl_release         :  u32,
+    pub dl_budget          :  u32,
+    pub dl_misses          :  u32,
+}
+
+impl PCB {
+    /* Copy in a name, cut short at the first NUL or when the buffer is full. */
+    pub fn set_name(&mut self, name : &[u8]) {
+        let mut len = 0;
+        while len < name.len() && len < PROC_NAME_LEN - 1 && name[len] != 0 {
+            len += 1;
+        }
+
+        self.name = [0; PROC_NAME_LEN];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+
+    pub fn name_len(&self) -> usize {
+        let mut len = 0;
+        while len < PROC_NAME_LEN && self.name[len] != 0 {
+            len += 1;
+        }
+        return len;
+    }
 }
 
 pub struct scheduler {
     pub current: *mut PCB,
-    pub schedule: *mut rbtree<u32, *mut PCB>,
+    /* Runs whenever nothing else can, so `current` is never null after boot */
+    pub idle: *mut PCB,
+    /* Fair-class processes, ordered by the build-time policy */
+    pub policy: Policy,
+    /* Deadline processes with budget left, keyed on absolute deadline */
+    pub deadlines: *mut rbtree<u32, *mut PCB>,
+    /* Deadline processes that used up their budget, keyed on next release */
+    pub throttled: *mut rbtree<u32, *mut PCB>,
+    /* Every live process (running, runnable or blocked), keyed on PID */
+    pub procs: *mut rbtree<i32, *mut PCB>,
+    pub dl_util: u32,
+    pub ticks: u32,
+    pub idle_ticks: u32,
+    /* System-wide accounting, in mtime ticks */
+    pub boot_time: u64,
+    pub idle_time: u64,
+    pub irq_time: u64,
+    pub switches: u32,
+    /* Traps taken, and mcycle cycles spent handling them and switching */
+    pub traps: u32,
+    pub trap_cycle_total: u64,
+    pub switch_cycles: u64,
+    trap_start: u64,
+    trap_cycles: u64,
+    switch_start: u64,
+    trap_pcb: *mut PCB,
+    /* mepc of a trap taken before any process was running */
+    trap_pc: u32,
+    /* Kernel context of the boot code, switched away from for good by the
+     * first schedule */
+    boot_kctx: kcontext,
+    /* The process being switched away from while reschedule picks the next
+     * one. current is already cleared then, but we are still on its kernel
+     * stack. */
+    prev: *mut PCB,
+    /* Exited process to reap once we are off its kernel stack */
+    reap_next: *mut PCB,
+    /* Whose registers are in the FPU, and whether they changed since */
+    #[cfg(feature = "fpu")]
+    fp_owner: *mut PCB,
+    #[cfg(feature = "fpu")]
+    fp_dirty: bool,
     pub next_pid: i32,
 }
 
@@ -37,244 +242,1262 @@ impl scheduler {
     pub const fn new() -> Self{
         scheduler{
             current: core::ptr::null::<PCB>() as *mut PCB,
-            schedule: core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            idle: core::ptr::null::<PCB>() as *mut PCB,
+            policy: Policy::new(),
+            deadlines: core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            throttled: core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            procs: core::ptr::null::<rbtree<i32, *mut PCB>>() as *mut rbtree<i32, *mut PCB>,
+            dl_util: 0,
+            ticks: 0,
+            idle_ticks: 0,
+            boot_time: 0,
+            idle_time: 0,
+            irq_time: 0,
+            switches: 0,
+            traps: 0,
+            trap_cycle_total: 0,
+            switch_cycles: 0,
+            trap_start: 0,
+            trap_cycles: 0,
+            switch_start: 0,
+            trap_pcb: core::ptr::null::<PCB>() as *mut PCB,
+            trap_pc: 0,
+            boot_kctx: kcontext::new(),
+            prev: core::ptr::null::<PCB>() as *mut PCB,
+            reap_next: core::ptr::null::<PCB>() as *mut PCB,
+            #[cfg(feature = "fpu")]
+            fp_owner: core::ptr::null::<PCB>() as *mut PCB,
+            #[cfg(feature = "fpu")]
+            fp_dirty: false,
             next_pid: 0,
         }
     }
 
     pub fn init(&mut self) {
         reset_timers();
-        self.schedule = kmalloc(core::mem::size_of::<rbtree<u32, *mut PCB>>() as u32)
-                   as *mut rbtree<u32, *mut PCB>;
+        self.boot_time = read_mtime();
+        self.policy.init();
+        self.deadlines = new_tree();
+        self.throttled = new_tree();
         unsafe{
-            *(self.schedule) = rbtree::new();
+            self.procs = Box::into_raw(Box::new(rbtree::with_cache(&mut PROC_NODES)));
+            self.idle  = self.new_idle();
+        }
+    }
+
+    unsafe fn new_idle(&mut self) -> *mut PCB {
+        let pcb: *mut PCB = PCB_CACHE.alloc();
+        let stack: *mut u32 = kmalloc(IDLE_STACK_SIZE);
+
+        if pcb.is_null() || stack.is_null() {
+            println!("could not allocate the idle task!");
+            crate::abort();
+        }
+
+        if !scheduler::new_kstack(pcb) {
+            println!("could not allocate the idle task!");
+            crate::abort();
+        }
+        (*pcb).context[2]    = stack as u32 + IDLE_STACK_SIZE;
+        (*pcb).stack_pointer = stack as u32;
+        (*pcb).stack_size    = IDLE_STACK_SIZE;
+        (*pcb).pid           = IDLE_PID;
+        (*pcb).tgid          = IDLE_PID;
+        (*pcb).pc            = idle_loop as u32;
+        (*pcb).waitpid       = -1;
+        (*pcb).join_tid      = -1;
+        (*pcb).set_name(b"idle");
+        (*pcb).class         = SchedClass::Fair;
+
+        return pcb;
+    }
+
+    pub unsafe fn update_schedule(&mut self) {
+        self.ticks += 1;
+
+        /* Sleeps are counted in ticks, whatever else gets scheduled */
+        let now = read_mtime();
+        (*self.procs).for_each(|_, pcb| {
+            if (**pcb).sleep > 0 {
+                (**pcb).sleep -= 1;
+                if (**pcb).sleep == 0 {
+                    (**pcb).wake_stamp = now;
+                }
+            }
+        });
+        self.reschedule(true);
+    }
+
+    /* Called once a syscall has finished. If it left the caller blocked
+     * (sleeping, waiting for a child or exiting), switch to another process
+     * now instead of letting it spin until the next tick. */
+    pub unsafe fn block(&mut self) {
+        let pcb = self.current;
+        if pcb.is_null() || pcb == self.idle || !scheduler::blocked(pcb) {
+            return;
+        }
+        self.reschedule(false);
+    }
+
+    /* Block the current process in the middle of a syscall until someone
+     * calls wakeup(chan). Returns once it has been scheduled again. */
+    pub unsafe fn sleep_on(&mut self, chan: u32) {
+        let pcb = self.current;
+        if pcb.is_null() || pcb == self.idle {
+            return;
         }
+        (*pcb).wchan = chan;
+        self.reschedule(false);
     }
 
-    pub unsafe fn update_schedule(&mut self, mut mepc: u32)-> u32 {
+    /* Make every process sleeping on `chan` runnable again. */
+    pub unsafe fn wakeup(&mut self, chan: u32) {
+        let now = read_mtime();
+        (*self.procs).for_each(|_, pcb| {
+            if (**pcb).wchan == chan {
+                (**pcb).wchan      = 0;
+                (**pcb).wake_stamp = now;
+            }
+        });
+    }
+
+    unsafe fn blocked(pcb: *mut PCB) -> bool {
+        return (*pcb).zombie || (*pcb).sleep > 0 || (*pcb).waitpid != -1
+            || (*pcb).join_tid != -1 || (*pcb).wchan != 0;
+    }
+
+    /* Put the current process back on the run queues and switch to the next
+     * one. Returns when the current process is next picked. `tick` is false
+     * when the process gave up the CPU itself, in which case no deadline
+     * budget is charged. */
+    unsafe fn reschedule(&mut self, tick: bool) {
         if self.current.is_null() { 
-            // println!("CURRENT WAS NULL -- looking for process");
-            let old_mepc = mepc;
-            mepc = self.schedule_next(mepc);
-            if old_mepc != mepc {
-                println!("found a process to run: {:X}", mepc);
+            let next = self.schedule_next();
+            if next != self.idle {
+                println!("found a process to run: {:X}", (*next).pc);
             }
             reset_timers();
-            return mepc; 
+            let boot = &mut self.boot_kctx as *mut kcontext;
+            self.switch_to(boot, next);
+            return;
         }
-        (*self.current).context   = GLOBAL_CTX;
-        (*self.current).vruntime += (*self.current).QM;
-        (*self.current).pc        = mepc;
 
-        self.add_to_tree((*self.current).vruntime, self.current);
+        let prev = self.current;
+
+        /* Going to sleep, waiting or exiting means the CPU was given up */
+        let voluntary = scheduler::blocked(prev);
+        self.charge(prev, read_mtime());
+
+        if prev == self.idle {
+            self.idle_ticks += 1;
+        } else if !(*prev).zombie {
+            match (*prev).class {
+                SchedClass::Fair     => {
+                    if voluntary {
+                        self.policy.yield_now(prev);
+                    } else {
+                        self.policy.tick(prev);
+                    }
+                },
+                SchedClass::Deadline => if tick { self.charge_deadline(prev) },
+            }
+
+            self.enqueue_or_exit(prev);
+        }
 
         (self.current) = core::ptr::null::<PCB>() as *mut PCB;
+        self.prev      = prev;
+
+        let next = self.schedule_next();
 
-        mepc = self.schedule_next(mepc);
         reset_timers();
 
-        return mepc;
+        if next == prev {
+            self.prev = core::ptr::null::<PCB>() as *mut PCB;
+            return;
+        }
+
+        self.switches += 1;
+        if prev != self.idle {
+            if voluntary {
+                (*prev).nvcsw += 1;
+            } else {
+                (*prev).nivcsw += 1;
+            }
+        }
+
+        if prev != self.idle && (*prev).zombie {
+            let leader = scheduler::group_leader(prev);
+            if (*leader).parent.is_null() && scheduler::group_done(leader) {
+                self.reap_next = leader;
+            }
+        }
+
+        self.switch_to(&mut (*prev).kctx as *mut kcontext, next);
     }
 
-    pub unsafe fn schedule_next(&mut self, mut mepc: u32) -> u32 {
-        let waiting = kmalloc(((*self.schedule).len as u32) * core::mem::size_of::<*mut PCB>() as u32)
-            as *mut *mut PCB;
+    /* Move onto `next`'s kernel stack. The time spent in this trap so far is
+     * billed to whoever took it; the rest goes to whoever we come back as. */
+    unsafe fn switch_to(&mut self, save: *mut kcontext, next: *mut PCB) {
+        self.switch_start = read_mcycle();
+        self.charge_trap();
+        #[cfg(feature = "fpu")]
+        self.fp_switch(next);
+        asm!("csrw mscratch, $0" :: "r"(&(*next).context as *const [u32; 32]) :: "volatile");
+        swtch(save, &(*next).kctx as *const kcontext);
+        self.switched_in();
+    }
 
-        let mut n_waiting = 0;
+    /* First thing run by a process once it is back on its kernel stack. */
+    unsafe fn switched_in(&mut self) {
+        self.switch_cycles += read_mcycle().wrapping_sub(self.switch_start);
+        self.trap_start     = read_mtime();
+        self.trap_cycles    = read_mcycle();
+        self.trap_pcb       = self.current;
+        self.prev           = core::ptr::null::<PCB>() as *mut PCB;
 
-        let all_pcbs = self.collect_all_procs();
-        loop {
-            if let Some((time, pcb)) = (*self.schedule).first() {
-                (*self.schedule).delete(*time);
-                if !(*(*pcb)).kill {
-                    if (*(*pcb)).waitpid != -1 {
-                        if scheduler::array_of_pcbs_has_pid(all_pcbs, (*(*pcb)).waitpid) {
-                            *waiting.offset(n_waiting) = *pcb;
-                            n_waiting += 1;
-                        } else {
-                            self.current = *pcb;
-                            (*self.current).waitpid = -1;
-                            break;
-                        }
-                    } else if (*(*pcb)).sleep <= 0 {
-                        self.current = *pcb;
-                        break;
-                    } else {
-                        *waiting.offset(n_waiting) = *pcb;
-                        n_waiting += 1;
-                    }
-                }
+        let dead = self.reap_next;
+        if !dead.is_null() {
+            self.reap_next = core::ptr::null::<PCB>() as *mut PCB;
+            self.reap(dead);
+        }
+    }
+
+    /* Only the FPU's owner runs with FP enabled; anyone else traps on their
+     * first FP instruction and gets the registers loaded by fp_fault(). */
+    #[cfg(feature = "fpu")]
+    unsafe fn fp_switch(&mut self, next: *mut PCB) {
+        if fpu::fs() == fpu::FS_DIRTY {
+            self.fp_dirty = true;
+        }
+        fpu::set_fs(if next == self.fp_owner { fpu::FS_CLEAN } else { fpu::FS_OFF });
+    }
+
+    /* Called for an illegal instruction trap. If FP was off, hand the FPU
+     * to the current process and return true so the instruction is run
+     * again; otherwise it really was illegal. */
+    #[cfg(feature = "fpu")]
+    pub unsafe fn fp_fault(&mut self) -> bool {
+        let pcb = self.current;
+        if pcb.is_null() || fpu::fs() != fpu::FS_OFF {
+            return false;
+        }
+
+        fpu::set_fs(fpu::FS_CLEAN);
+        if pcb != self.fp_owner {
+            let owner = self.fp_owner;
+            if !owner.is_null() && self.fp_dirty {
+                fpu::save(&mut (*owner).fp as *mut fpu::fp_state);
+            }
+            fpu::restore(&(*pcb).fp as *const fpu::fp_state);
+            fpu::set_fs(fpu::FS_CLEAN);
+            self.fp_owner = pcb;
+            self.fp_dirty = false;
+        }
+        return true;
+    }
+
+    /* Give `pcb` a kernel stack whose first switch lands in _proc_entry,
+     * which returns to user mode as if from a trap. */
+    unsafe fn new_kstack(pcb: *mut PCB) -> bool {
+        let kstack = kmalloc(KSTACK_SIZE);
+        if kstack.is_null() {
+            return false;
+        }
+        (*pcb).kstack  = kstack;
+        (*pcb).ksp     = scheduler::kstack_top(pcb);
+        (*pcb).kctx    = kcontext::new();
+        (*pcb).kctx.ra = _proc_entry as u32;
+        (*pcb).kctx.sp = scheduler::kstack_top(pcb);
+        return true;
+    }
+
+    fn kstack_top(pcb: *mut PCB) -> u32 {
+        return unsafe { (*pcb).kstack as u32 + KSTACK_SIZE };
+    }
+
+    /* Bill the time since `pcb` was dispatched. Whatever wasn't spent in
+     * traps was user time; for the idle task it is all idle time. */
+    unsafe fn charge(&mut self, pcb: *mut PCB, now: u64) {
+        let ran = now.saturating_sub((*pcb).last_run);
 
-                let sp = (*(*pcb)).stack_pointer as *mut u32;
-                if sp.is_null() {
-                    println!("sp is NULL????");
+        if pcb == self.idle {
+            self.idle_time += ran;
+        } else {
+            (*pcb).utime += ran.saturating_sub((*pcb).slice_ktime);
+        }
+        (*pcb).slice_ktime = 0;
+    }
+
+    /* Called by the trap handler on entry and exit. Time between the two is
+     * interrupt time, and kernel time for whichever process trapped. */
+    pub unsafe fn trap_enter(&mut self, pc: u32) {
+        self.trap_start  = read_mtime();
+        self.trap_cycles = read_mcycle();
+        self.trap_pcb    = self.current;
+        self.traps      += 1;
+
+        let pcb = self.current;
+        if pcb.is_null() {
+            self.trap_pc = pc;
+        } else {
+            (*pcb).pc    = pc;
+        }
+    }
+
+    pub unsafe fn trap_exit(&mut self) -> u32 {
+        self.charge_trap();
+
+        let pcb = self.current;
+        if pcb.is_null() {
+            return self.trap_pc;
+        }
+        return (*pcb).pc;
+    }
+
+    /* The registers of the trapped process, as saved by asm/trap.S: its
+     * PCB, or the boot frame before the first process runs. */
+    pub unsafe fn frame(&mut self) -> *mut [u32; 32] {
+        if self.current.is_null() {
+            return &mut BOOT_FRAME as *mut [u32; 33] as *mut [u32; 32];
+        }
+        return &mut (*self.current).context as *mut [u32; 32];
+    }
+
+    unsafe fn charge_trap(&mut self) {
+        let spent = read_mtime().saturating_sub(self.trap_start);
+        self.irq_time += spent;
+        self.trap_cycle_total += read_mcycle().wrapping_sub(self.trap_cycles);
+
+        let pcb = self.trap_pcb;
+        if !pcb.is_null() && pcb != self.idle {
+            (*pcb).ktime       += spent;
+            (*pcb).slice_ktime += spent;
+        }
+        self.trap_pcb = core::ptr::null::<PCB>() as *mut PCB;
+    }
+
+    /* Charge one tick of runtime to a deadline process. A job that is still
+     * running once its absolute deadline has passed counts as a miss and is
+     * pushed to its next period. */
+    unsafe fn charge_deadline(&mut self, pcb: *mut PCB) {
+        if (*pcb).dl_budget > 0 {
+            (*pcb).dl_budget -= 1;
+        }
+
+        self.check_deadline(pcb);
+    }
+
+    /* Count a miss, and give up the rest of the budget until the next
+     * period, if the deadline has passed with budget still left. Returns
+     * whether it had. */
+    unsafe fn check_deadline(&mut self, pcb: *mut PCB) -> bool {
+        if self.ticks >= (*pcb).dl_abs_deadline && (*pcb).dl_budget > 0 {
+            (*pcb).dl_misses += 1;
+            (*pcb).dl_budget  = 0;
+            println!("pid {} missed its deadline ({} misses)", (*pcb).pid, (*pcb).dl_misses);
+            return true;
+        }
+        return false;
+    }
+
+    /* Put a process back on the tree that belongs to its class. Returns
+     * false if there was no memory for a tree node. */
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool {
+        return match (*pcb).class {
+            SchedClass::Fair     => self.policy.enqueue(pcb),
+            SchedClass::Deadline => {
+                if (*pcb).dl_budget == 0 {
+                    add_to_tree(self.throttled, (*pcb).dl_release, pcb)
+                } else {
+                    add_to_tree(self.deadlines, (*pcb).dl_abs_deadline, pcb)
                 }
-                kfree(sp);
+            }
+        };
+    }
+
+    /* Take a process off whichever run queue it is on. Returns false if it
+     * isn't on one: it is running, or set aside in pick_runnable. */
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool {
+        return match (*pcb).class {
+            SchedClass::Fair     => self.policy.dequeue(pcb),
+            SchedClass::Deadline => remove_from_tree(self.deadlines, pcb)
+                                    || remove_from_tree(self.throttled, pcb),
+        };
+    }
+
+    /* enqueue, for a process that is already in the scheduler. One that
+     * can't be queued would never run again, so it exits instead, and the
+     * OOM killer gets to work on the rest of the heap. */
+    unsafe fn enqueue_or_exit(&mut self, pcb: *mut PCB) {
+        if !self.enqueue(pcb) {
+            println!("OOM: no room to queue pid {}", (*pcb).pid);
+            self.oom();
+            self.exit(pcb, EXIT_OOM);
+        }
+    }
+
+    /* Move every throttled deadline process whose next period has started
+     * back onto the deadline tree with a fresh budget. */
+    unsafe fn replenish_deadlines(&mut self) {
+        loop {
+            /* The key can differ from dl_release if add_to_tree moved it */
+            let (release, pcb) = match (*self.throttled).first() {
+                Some((release, pcb)) if *release <= self.ticks => (*release, *pcb),
+                _                                              => break,
+            };
+            (*self.throttled).delete(release);
+
+            while (*pcb).dl_release <= self.ticks {
+                (*pcb).dl_abs_deadline = (*pcb).dl_release + (*pcb).dl_deadline;
+                (*pcb).dl_release     += (*pcb).dl_period;
+            }
+            (*pcb).dl_budget = (*pcb).dl_runtime;
+
+            self.enqueue_or_exit(pcb);
+        }
+    }
+
+    /* Can this process run right now? A process in WAITPID wakes once a
+     * matching child is a zombie, or once there is no matching child left
+     * so that the call can fail. */
+    unsafe fn runnable(&mut self, pcb: *mut PCB) -> bool {
+        if (*pcb).waitpid != -1 {
+            let mut has_child = false;
+            let zombie = self.find_zombie_child(pcb, (*pcb).waitpid, &mut has_child);
+            return !zombie.is_null() || !has_child;
+        }
+        if (*pcb).join_tid != -1 {
+            let thread = self.get_pcb((*pcb).join_tid);
+            return thread.is_null() || (*thread).zombie;
+        }
+
+        return (*pcb).sleep <= 0 && (*pcb).wchan == 0;
+    }
+
+    /* Take candidates from `next` until one can run. Processes that can't
+     * run yet are handed back to `requeue` once a pick has been made. */
+    unsafe fn pick_runnable(&mut self,
+                            next: unsafe fn(&mut scheduler) -> *mut PCB,
+                            requeue: unsafe fn(&mut scheduler, *mut PCB)) -> *mut PCB {
+        let mut waiting = core::ptr::null::<PCB>() as *mut PCB;
+        let mut last    = core::ptr::null::<PCB>() as *mut PCB;
+        let mut picked  = core::ptr::null::<PCB>() as *mut PCB;
+
+        loop {
+            let pcb = next(self);
+            if pcb.is_null() {
+                break;
+            }
+
+            if (*pcb).kill {
+                self.exit(pcb, (*pcb).kill_status);
+                continue;
+            }
+
+            if self.runnable(pcb) {
+                picked = pcb;
+                break;
+            }
+
+            /* Keep them in the order they came out, for round robin */
+            (*pcb).wait_next = core::ptr::null::<PCB>() as *mut PCB;
+            if last.is_null() {
+                waiting = pcb;
+            } else {
+                (*last).wait_next = pcb;
+            }
+            last = pcb;
+        }
+
+        while !waiting.is_null() {
+            let pcb = waiting;
+            waiting = (*pcb).wait_next;
+            requeue(self, pcb);
+        }
+
+        return picked;
+    }
+
+    /* A runnable process whose deadline passed while others held the CPU
+     * has missed it as surely as one that ran out of time, so it is
+     * throttled until its next period instead of being picked. */
+    unsafe fn next_deadline(&mut self) -> *mut PCB {
+        loop {
+            let pcb = policy::tree_pop_first(self.deadlines);
+            if pcb.is_null() || (*pcb).kill || !self.runnable(pcb) || !self.check_deadline(pcb) {
+                return pcb;
+            }
+            self.enqueue_or_exit(pcb);
+        }
+    }
+
+    unsafe fn requeue_deadline(&mut self, pcb: *mut PCB) {
+        self.enqueue_or_exit(pcb);
+    }
+
+    unsafe fn next_fair(&mut self) -> *mut PCB {
+        return self.policy.pick_next();
+    }
+
+    unsafe fn requeue_fair(&mut self, pcb: *mut PCB) {
+        self.enqueue_or_exit(pcb);
+    }
+
+    /* Pick the next process and make it current. Falls back to the idle
+     * task, so there is always something to switch to. */
+    unsafe fn schedule_next(&mut self) -> *mut PCB {
+        self.replenish_deadlines();
+
+        /* Earliest deadline first, then the fair tree */
+        self.current = self.pick_runnable(scheduler::next_deadline, scheduler::requeue_deadline);
+        if self.current.is_null() {
+            self.current = self.pick_runnable(scheduler::next_fair, scheduler::requeue_fair);
+        }
+
+        if self.current.is_null() {
+            self.current = self.idle;
+        }
+
+        let now = read_mtime();
+        let pcb = self.current;
+        if (*pcb).waitpid != -1 {
+            (*pcb).context[10] = self.finish_wait(pcb) as u32;
+        } else if (*pcb).join_tid != -1 {
+            (*pcb).context[10] = self.finish_join(pcb) as u32;
+        }
+        (*pcb).last_run    = now;
+        (*pcb).slice_ktime = 0;
+        if (*pcb).wake_stamp != 0 {
+            let latency = now.saturating_sub((*pcb).wake_stamp);
+            (*pcb).wake_latency_total += latency;
+            if latency > (*pcb).wake_latency_max {
+                (*pcb).wake_latency_max = latency;
+            }
+            (*pcb).wakeups   += 1;
+            (*pcb).wake_stamp = 0;
+        }
+
+        return pcb;
+    }
+
+    /* Turn a process into a zombie holding `status` until its parent reaps
+     * it. The caller must already have taken it off the run queues. Its
+     * children are handed to init; a process nobody can wait for is reaped
+     * straight away. */
+    pub unsafe fn exit(&mut self, pcb: *mut PCB, status: i32) {
+        if (*pcb).zombie {
+            return;
+        }
 
+        (*pcb).zombie      = true;
+        (*pcb).kill        = false;
+        (*pcb).exit_status = status;
+
+        let leader = scheduler::group_leader(pcb);
+        let parent = (*leader).parent;
+        if !parent.is_null() && scheduler::group_done(leader)
+           && ((*parent).waitpid == WAIT_ANY || (*parent).waitpid == (*leader).pid) {
+            (*parent).wake_stamp = read_mtime();
+        }
+
+        if (*pcb).class == SchedClass::Deadline {
+            self.dl_util -= scheduler::dl_utilisation((*pcb).dl_runtime, (*pcb).dl_period);
+            (*pcb).class  = SchedClass::Fair;
+        }
+
+        let init = self.get_pcb(INIT_PID);
+        let mut child = (*pcb).children;
+        while !child.is_null() {
+            let next = (*child).sibling;
+            (*child).sibling = core::ptr::null::<PCB>() as *mut PCB;
+            (*child).parent  = core::ptr::null::<PCB>() as *mut PCB;
+            if init.is_null() || init == pcb {
+                /* If we are still on its kernel stack, reschedule reaps it
+                 * after switching away, now that it has no parent */
+                if scheduler::group_done(child) && !self.running_in(child) {
+                    self.reap(child);
+                }
             } else {
-                if !all_pcbs.is_null() {
-                    kfree(all_pcbs as *mut u32);
+                scheduler::adopt(init, child);
+            }
+            child = next;
+        }
+        (*pcb).children = core::ptr::null::<PCB>() as *mut PCB;
+
+        /* A group nobody can wait for is reaped once its last thread exits,
+         * but not while we are still running on one of its kernel stacks.
+         * reschedule reaps that one after switching away. */
+        if (*leader).parent.is_null() && scheduler::group_done(leader) && !self.running_in(leader) {
+            self.reap(leader);
+        }
+    }
+
+    /* Are we running on the kernel stack of a thread in `leader`'s group?
+     * During reschedule that is prev, not current. */
+    unsafe fn running_in(&self, leader: *mut PCB) -> bool {
+        for pcb in [self.current, self.prev].iter() {
+            if !pcb.is_null() && *pcb != self.idle && scheduler::group_leader(*pcb) == leader {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    /* End every thread in the current process with `status`. The others go
+     * the next time they are picked, the same way as after KILL. */
+    pub unsafe fn exit_group(&mut self, status: i32) {
+        let pcb = self.current;
+        if pcb.is_null() {
+            return;
+        }
+
+        let leader = scheduler::group_leader(pcb);
+        let mut thread = leader;
+        while !thread.is_null() {
+            if thread != pcb && !(*thread).zombie {
+                (*thread).kill        = true;
+                (*thread).kill_status = status;
+            }
+            thread = if thread == leader { (*leader).threads } else { (*thread).thread_next };
+        }
+        self.exit(pcb, status);
+    }
+
+    /* The thread that owns the process `pcb` belongs to. */
+    pub unsafe fn group_leader(pcb: *mut PCB) -> *mut PCB {
+        if (*pcb).leader.is_null() {
+            return pcb;
+        }
+        return (*pcb).leader;
+    }
+
+    /* Move the current process's break to `addr`, or just report where it
+     * is if `addr` is 0. The break is the end of the group's arena, a block
+     * of user memory made on first use that the group's allocator carves up
+     * for itself. The arena grows in place, so only into free pages right
+     * after it, and not past the group's mem_limit. Returns the new break,
+     * or the old one if it could not move; 0 if there is no arena. */
+    pub unsafe fn brk(&mut self, addr: u32) -> u32 {
+        if self.current.is_null() || self.current == self.idle {
+            return 0;
+        }
+        let leader = scheduler::group_leader(self.current);
+
+        if (*leader).arena.is_null() {
+            if !scheduler::within_limit(leader, UPAGE_SIZE) {
+                return 0;
+            }
+            let arena = ualloc_arena(UPAGE_SIZE);
+            if arena.is_null() {
+                self.oom();
+                return 0;
+            }
+            (*leader).arena      = arena;
+            (*leader).arena_size = UPAGE_SIZE;
+            (*leader).brk        = arena as u32;
+        }
+
+        let base = (*leader).arena as u32;
+        if addr < base {
+            return (*leader).brk;
+        }
+
+        /* Keep at least a page so the arena stays where it is */
+        let want = core::cmp::max(upage_round(addr - base), UPAGE_SIZE);
+        let have = (*leader).arena_size;
+        if want > have {
+            if !scheduler::within_limit(leader, want - have) {
+                return (*leader).brk;
+            }
+            if !uresize((*leader).arena, have, want) {
+                if user_stats().free < want - have {
+                    self.oom();
                 }
-                if !waiting.is_null() {
-                    kfree(waiting as *mut u32);
+                return (*leader).brk;
+            }
+        } else if want < have {
+            uresize((*leader).arena, have, want);
+        }
+
+        (*leader).arena_size = want;
+        (*leader).brk        = addr;
+        return addr;
+    }
+
+    /* Bytes of memory held by the thread group led by `leader`: every
+     * thread's PCB and stacks, the program image and its arena. */
+    pub unsafe fn mem_usage(leader: *mut PCB) -> u32 {
+        let mut total = (*leader).arena_size + upage_round((*leader).image_size);
+
+        let mut thread = leader;
+        while !thread.is_null() {
+            total += core::mem::size_of::<PCB>() as u32;
+            if (*thread).stack_pointer != 0 {
+                total += upage_round((*thread).stack_size);
+            }
+            if !(*thread).kstack.is_null() {
+                total += ksize((*thread).kstack);
+            }
+            thread = if thread == leader { (*leader).threads } else { (*thread).thread_next };
+        }
+        return total;
+    }
+
+    /* Which process the memory at [addr, addr + len) belongs to, and what it
+     * holds for it, if it overlaps any process's PCB, stacks, image or
+     * arena. Used to label memory dumps. */
+    pub unsafe fn owner_of(&self, addr: u32, len: u32) -> Option<(i32, &'static str)> {
+        let overlaps = |start: u32, size: u32| start != 0 && start < addr.saturating_add(len) && addr < start.saturating_add(size);
+        let check = |pcb: *mut PCB| -> Option<(i32, &'static str)> {
+            if overlaps((*pcb).kstack as u32, KSTACK_SIZE) {
+                return Some(((*pcb).pid, "kernel stack"));
+            }
+            if overlaps((*pcb).stack_pointer, (*pcb).stack_size) {
+                return Some(((*pcb).pid, "stack"));
+            }
+            if overlaps((*pcb).image as u32, (*pcb).image_size) {
+                return Some(((*pcb).pid, "image"));
+            }
+            if overlaps((*pcb).arena as u32, (*pcb).arena_size) {
+                return Some(((*pcb).pid, "arena"));
+            }
+            if overlaps(pcb as u32, core::mem::size_of::<PCB>() as u32) {
+                return Some(((*pcb).pid, "pcb"));
+            }
+            return None;
+        };
+
+        let mut found = if self.idle.is_null() { None } else { check(self.idle) };
+        if !self.procs.is_null() {
+            (*self.procs).for_each(|_, pcb| {
+                if found.is_none() {
+                    found = check(*pcb);
                 }
+            });
+        }
+        return found;
+    }
+
+    /* What the thread group led by `leader` holds, along with every process
+     * below it. A group's mem_limit covers all of that. */
+    unsafe fn charged(leader: *mut PCB) -> u32 {
+        let mut total = scheduler::mem_usage(leader);
+
+        let mut thread = leader;
+        while !thread.is_null() {
+            let mut child = (*thread).children;
+            while !child.is_null() {
+                total = total.saturating_add(scheduler::charged(child));
+                child = (*child).sibling;
+            }
+            thread = if thread == leader { (*leader).threads } else { (*thread).thread_next };
+        }
+        return total;
+    }
+
+    /* How many more bytes the group led by `leader` may take before it, or
+     * any process above it, goes over its mem_limit. None if no limit
+     * applies. */
+    unsafe fn headroom(leader: *mut PCB) -> Option<u32> {
+        let mut left: Option<u32> = None;
+        let mut group = leader;
+        while !group.is_null() {
+            if (*group).mem_limit != 0 {
+                let room = (*group).mem_limit.saturating_sub(scheduler::charged(group));
+                left = Some(left.map_or(room, |l| core::cmp::min(l, room)));
             }
-            return mepc;
+            group = if (*group).parent.is_null() { null::<PCB>() as *mut PCB } else { scheduler::group_leader((*group).parent) };
         }
+        return left;
+    }
+
+    /* Whether the thread group led by `leader` may take `n_bytes` more of
+     * the heap without it or a process above it going over its mem_limit. */
+    unsafe fn within_limit(leader: *mut PCB, n_bytes: u32) -> bool {
+        return scheduler::headroom(leader).map_or(true, |left| n_bytes <= left);
+    }
+
+    /* The heap has run out. Kill the thread group holding the most of it so
+     * that it is given back when the group is reaped; whatever ran out still
+     * fails. Init is never picked, and if the biggest group is already on
+     * its way out nothing else is killed. */
+    pub unsafe fn oom(&mut self) {
+        let mut victim = null::<PCB>() as *mut PCB;
+        let mut most   = 0;
+        (*self.procs).for_each(|_, pcb| {
+            let pcb = *pcb;
+            if !(*pcb).leader.is_null() || (*pcb).pid == INIT_PID || scheduler::group_done(pcb) {
+                return;
+            }
+            let usage = scheduler::mem_usage(pcb);
+            if usage > most {
+                most   = usage;
+                victim = pcb;
+            }
+        });
 
-        for i in 0..n_waiting {
-            self.add_to_tree((*(*waiting.offset(i))).vruntime, *waiting.offset(i));
+        if victim.is_null() {
+            println!("OOM: out of memory and nothing to kill");
+            return;
         }
-        let mut ptr = all_pcbs;
-        while !(*ptr).is_null() {
-            (*(*ptr)).sleep -= 1;
-            ptr = ptr.offset(1)
+        if (*victim).kill {
+            return;
         }
+        println!("OOM: killing pid {} ({} bytes)", (*victim).pid, most);
+        /* The full maps take a while over the UART, so only when debugging
+         * the heap; MEMINFO can print them otherwise */
+        #[cfg(feature = "heap_debug")]
+        {
+            heap_dump();
+            user_dump();
+        }
+        self.kill(victim, EXIT_OOM);
+    }
 
-        GLOBAL_CTX = (*self.current).context;
+    /* Make `pcb` exit with `status`. A group leader takes all of its
+     * threads with it. Threads sitting on a run queue are taken off it and
+     * exit now; the rest do the next time they come up to run. */
+    pub unsafe fn kill(&mut self, pcb: *mut PCB, status: i32) {
+        (*pcb).kill        = true;
+        (*pcb).kill_status = status;
+        if !(*pcb).leader.is_null() {
+            if self.dequeue(pcb) {
+                self.exit(pcb, status);
+            }
+            return;
+        }
 
-        if !all_pcbs.is_null() {
-            kfree(all_pcbs as *mut u32);
+        let mut thread = (*pcb).threads;
+        while !thread.is_null() {
+            (*thread).kill        = true;
+            (*thread).kill_status = status;
+            thread = (*thread).thread_next;
         }
-        if !waiting.is_null() {
-            kfree(waiting as *mut u32);
+
+        /* One at a time, since the exit of the last live thread can reap the
+         * whole group */
+        loop {
+            let mut victim = null::<PCB>() as *mut PCB;
+            let mut live   = 0;
+            let mut thread = pcb;
+            while !thread.is_null() {
+                if !(*thread).zombie {
+                    live += 1;
+                    if victim.is_null() && self.dequeue(thread) {
+                        victim = thread;
+                    }
+                }
+                thread = if thread == pcb { (*pcb).threads } else { (*thread).thread_next };
+            }
+            if victim.is_null() {
+                return;
+            }
+            self.exit(victim, status);
+            if live == 1 {
+                return;
+            }
         }
+    }
 
-        return (*self.current).pc;
+    /* A process only counts as exited once all of its threads have. */
+    unsafe fn group_done(leader: *mut PCB) -> bool {
+        if !(*leader).zombie {
+            return false;
+        }
+        let mut thread = (*leader).threads;
+        while !thread.is_null() {
+            if !(*thread).zombie {
+                return false;
+            }
+            thread = (*thread).thread_next;
+        }
+        return true;
     }
 
-    pub unsafe fn new_process(&mut self, stack_size: u32, ip: u32, QM: u32, data : *mut u32, mut data_len : u32, name : *const char) -> i32 {
-        let pcb: *mut PCB = kmalloc(core::mem::size_of::<PCB>() as u32) as *mut PCB;
-        let stack: *mut u32 = kmalloc(stack_size);
-        
-        if stack.is_null() {
-            println!("could not allocate process stack!");
+    /* Start a thread in the current process at `ip` with a0 = arg0 and
+     * a1 = arg1 on a stack of its own. Returns its thread ID, which comes
+     * from the same space as PIDs, or -1. The thread's stacks count towards
+     * the process's mem_limit. */
+    pub unsafe fn new_thread(&mut self, stack_size: u32, ip: u32, arg0: u32, arg1: u32) -> i32 {
+        let creator = self.current;
+        if creator.is_null() || creator == self.idle || stack_size == 0 {
+            return -1;
+        }
+        let leader = scheduler::group_leader(creator);
+        let cost = stack_size.saturating_add(core::mem::size_of::<PCB>() as u32 + KSTACK_SIZE);
+        if !scheduler::within_limit(leader, cost) {
+            return -1;
         }
 
-        let mut data_dst = core::ptr::null::<u32>() as *mut u32;
+        let pcb: *mut PCB = PCB_CACHE.alloc();
+        let stack: *mut u32 = ualloc(stack_size);
+        if pcb.is_null() || stack.is_null() {
+            if !pcb.is_null() {
+                PCB_CACHE.free(pcb);
+            }
+            if !stack.is_null() {
+                ufree(stack, stack_size);
+            }
+            self.oom();
+            return -1;
+        }
 
-        if data.is_null() {
-            data_len = 0;
-            data_dst = stack.offset(stack_size as isize);
-        } else {
-            data_dst = stack.offset((stack_size - data_len) as isize);
-            core::ptr::copy_nonoverlapping(data, data_dst, data_len as usize);
+        if !scheduler::new_kstack(pcb) {
+            ufree(stack, stack_size);
+            PCB_CACHE.free(pcb);
+            self.oom();
+            return -1;
         }
 
-//        (*pcb).context[1] = ra as u32; //function pointer to return address
-        (*pcb).context[10] = data_dst as u32;
-        (*pcb).context[11] = data_len;
-        (*pcb).context[2] = stack as u32 + stack_size;
+        (*pcb).context[2]    = (stack as u32 + stack_size) & !0xF;
+        (*pcb).context[3]    = (*creator).context[3];
+        (*pcb).context[10]   = arg0;
+        (*pcb).context[11]   = arg1;
         (*pcb).stack_pointer = stack as u32;
+        (*pcb).stack_size    = stack_size;
         (*pcb).pid           = self.next_pid;
-        (*pcb).vruntime      = ((*self.schedule).len as u32);
+        (*pcb).tgid          = (*leader).pid;
+        (*pcb).leader        = leader;
+        (*pcb).thread_next   = (*leader).threads;
+        (*leader).threads    = pcb;
         (*pcb).pc            = ip;
-        (*pcb).kill          = false;
-        (*pcb).QM            = QM;
+        (*pcb).QM            = (*creator).QM;
         (*pcb).waitpid       = -1;
-        (*pcb).sleep         = 0;
+        (*pcb).join_tid      = -1;
+        (*pcb).name          = (*creator).name;
+        (*pcb).class         = SchedClass::Fair;
+        (*pcb).wake_stamp    = read_mtime();
 
-        self.add_to_tree((*pcb).vruntime, pcb);
+        if !(*self.procs).insert((*pcb).pid, pcb) || !self.policy.admit(pcb) {
+            println!("could not queue the new thread!");
+            self.reap(pcb);
+            self.oom();
+            return -1;
+        }
         self.next_pid += 1;
-        
-        println!("new_process(): new pid = {}", (*pcb).pid);
 
         return (*pcb).pid;
     }
 
-    unsafe fn add_to_tree(&mut self, mut key : u32, val : *mut PCB) {
-        loop {
-            if let None = (*self.schedule).lookup(key) {
-                (*self.schedule).insert(key, val);
-                break;
-            }
+    /* Complete the THREAD_JOIN `pcb` is blocked in, which must be
+     * runnable(). Reaps the thread and stores its exit status. Returns 0, or
+     * -1 if the thread is already gone. */
+    pub unsafe fn finish_join(&mut self, pcb: *mut PCB) -> i32 {
+        let thread = self.get_pcb((*pcb).join_tid);
+        (*pcb).join_tid = -1;
+        if thread.is_null() {
+            return -1;
+        }
 
-            key += 1;
+        let status = self.reap(thread);
+        if (*pcb).wait_status != 0 {
+            *((*pcb).wait_status as *mut i32) = status;
         }
+        return 0;
     }
 
-    unsafe fn _tree_get_node_with_pid(node : *mut rbtree_node<u32, *mut PCB>, pid : i32) -> *mut rbtree_node<u32, *mut PCB> {
-        if (*(*node).val).pid == pid { return node; }
-
-        if !(*node).children[0].is_null() {
-            let l = scheduler::_tree_get_node_with_pid((*node).children[0], pid);
-            if !l.is_null() { return l; }
+    /* Complete the WAITPID `pcb` is blocked in, which must be runnable().
+     * Reaps the child, stores its exit status and returns its PID, or -1 if
+     * there was no matching child. */
+    pub unsafe fn finish_wait(&mut self, pcb: *mut PCB) -> i32 {
+        let mut has_child = false;
+        let child = self.find_zombie_child(pcb, (*pcb).waitpid, &mut has_child);
+        (*pcb).waitpid = -1;
+        if child.is_null() {
+            return -1;
         }
-        
-        if !(*node).children[1].is_null() {
-            let r = scheduler::_tree_get_node_with_pid((*node).children[1], pid);
-            if !r.is_null() { return r; }
+
+        let child_pid = (*child).pid;
+        let status    = self.reap(child);
+        if (*pcb).wait_status != 0 {
+            *((*pcb).wait_status as *mut i32) = status;
         }
+        return child_pid;
+    }
 
-        return core::ptr::null::<rbtree_node<u32, *mut PCB>>() as *mut rbtree_node<u32, *mut PCB>;
+    unsafe fn adopt(parent: *mut PCB, child: *mut PCB) {
+        (*child).parent    = parent;
+        (*child).sibling   = (*parent).children;
+        (*parent).children = child;
     }
 
-    pub unsafe fn tree_has_pid(&mut self, pid : i32) -> bool {
-        return scheduler::_tree_get_node_with_pid((*self.schedule).root, pid).is_null();
+    /* Find a zombie child of `parent` matching `pid` (or WAIT_ANY). Returns
+     * null if there is none yet. `has_child` reports whether any child
+     * matches at all. */
+    pub unsafe fn find_zombie_child(&mut self, parent: *mut PCB, pid: i32, has_child: &mut bool) -> *mut PCB {
+        *has_child = false;
+        let mut child = (*parent).children;
+        while !child.is_null() {
+            if pid == WAIT_ANY || pid == (*child).pid {
+                *has_child = true;
+                if scheduler::group_done(child) {
+                    return child;
+                }
+            }
+            child = (*child).sibling;
+        }
+        return core::ptr::null::<PCB>() as *mut PCB;
     }
 
-    pub unsafe fn get_pcb(&mut self, pid : i32) -> *mut PCB {
-        if (*self.current).pid == pid {
-            return self.current;
+    /* Release a zombie: unlink it from its parent and give its stack and PCB
+     * back to the heap. Reaping a thread group leader releases all of its
+     * threads, which must have exited too. Returns its exit status. */
+    pub unsafe fn reap(&mut self, pcb: *mut PCB) -> i32 {
+        let status = (*pcb).exit_status;
+
+        let leader = (*pcb).leader;
+        if leader.is_null() {
+            while !(*pcb).threads.is_null() {
+                self.reap((*pcb).threads);
+            }
+            if !(*pcb).arena.is_null() {
+                ufree((*pcb).arena, (*pcb).arena_size);
+            }
+        } else {
+            let mut link = &mut (*leader).threads as *mut *mut PCB;
+            while !(*link).is_null() {
+                if *link == pcb {
+                    *link = (*pcb).thread_next;
+                    break;
+                }
+                link = &mut (**link).thread_next as *mut *mut PCB;
+            }
         }
 
-        let node = scheduler::_tree_get_node_with_pid((*self.schedule).root, pid);
+        (*self.procs).delete((*pcb).pid);
+        if self.trap_pcb == pcb {
+            self.trap_pcb = core::ptr::null::<PCB>() as *mut PCB;
+        }
+        #[cfg(feature = "fpu")]
+        {
+            if self.fp_owner == pcb {
+                self.fp_owner = core::ptr::null::<PCB>() as *mut PCB;
+                self.fp_dirty = false;
+            }
+        }
 
-        if !node.is_null() {
-            return (*node).val;
+        let parent = (*pcb).parent;
+        if !parent.is_null() {
+            let mut link = &mut (*parent).children as *mut *mut PCB;
+            while !(*link).is_null() {
+                if *link == pcb {
+                    *link = (*pcb).sibling;
+                    break;
+                }
+                link = &mut (**link).sibling as *mut *mut PCB;
+            }
         }
 
-        return core::ptr::null::<PCB>() as *mut PCB;
+        let sp = (*pcb).stack_pointer as *mut u32;
+        if sp.is_null() {
+            println!("sp is NULL????");
+        } else {
+            ufree(sp, (*pcb).stack_size);
+        }
+        if !(*pcb).kstack.is_null() {
+            kfree((*pcb).kstack);
+        }
+        if !(*pcb).image.is_null() {
+            ufree((*pcb).image, (*pcb).image_size);
+        }
+        PCB_CACHE.free(pcb);
+
+        return status;
     }
 
-    unsafe fn _collect_all_procs(node : *mut rbtree_node<u32, *mut PCB>, array : *mut *mut PCB, idx : &mut u32) {
-        *array.offset(*idx as isize) = (*node).val;
+    /* Create a process that starts at `ip` with argc/argv/envp built from
+     * `args` (which may be null) on its stack. `image` is the program's
+     * memory from ualloc, if it has any, and becomes the process's on
+     * success. The process may hold at most `mem_limit` bytes of heap (see
+     * mem_usage), or if that is 0 whatever the process creating it has
+     * left; it is charged to its creator too, so it never gets more than
+     * that. Returns the new PID, or -1 if the arguments don't fit in half
+     * the stack, the stacks and image alone are over the limit, or the heap
+     * has no room for them. */
+    pub unsafe fn new_process(&mut self, stack_size: u32, ip: u32, QM: u32, args : *const proc_args, name : &[u8],
+                              mem_limit: u32, image: *mut u32, image_size: u32) -> i32 {
+        match loader::args_size(args) {
+            Ok(n) if n <= stack_size / 2 => {},
+            _                            => return -1,
+        }
 
-        *idx += 1;
+        let left = if !self.current.is_null() && self.current != self.idle {
+            scheduler::headroom(scheduler::group_leader(self.current))
+        } else {
+            None
+        };
+        let cost = stack_size.saturating_add(core::mem::size_of::<PCB>() as u32 + KSTACK_SIZE)
+                             .saturating_add(upage_round(image_size));
+        if left.map_or(false, |left| cost > left) || (mem_limit != 0 && cost > mem_limit) {
+            println!("new_process(): {} bytes of stack and image is over the limit", cost);
+            return -1;
+        }
+        let mem_limit = match left {
+            Some(left) if mem_limit == 0 || mem_limit > left => left,
+            _                                                => mem_limit,
+        };
 
-        if !(*node).children[0].is_null() {
-            scheduler::_collect_all_procs((*node).children[0], array, idx);
+        let pcb: *mut PCB = PCB_CACHE.alloc();
+        let stack: *mut u32 = ualloc(stack_size);
+        
+        if pcb.is_null() || stack.is_null() {
+            println!("could not allocate process stack!");
+            if !pcb.is_null() {
+                PCB_CACHE.free(pcb);
+            }
+            if !stack.is_null() {
+                ufree(stack, stack_size);
+            }
+            self.oom();
+            return -1;
         }
-        if !(*node).children[1].is_null() {
-            scheduler::_collect_all_procs((*node).children[1], array, idx);
+
+        if !scheduler::new_kstack(pcb) {
+            println!("could not allocate kernel stack!");
+            ufree(stack, stack_size);
+            PCB_CACHE.free(pcb);
+            self.oom();
+            return -1;
         }
+
+        (*pcb).stack_pointer = stack as u32;
+        (*pcb).stack_size    = stack_size;
+        (*pcb).image         = image;
+        (*pcb).image_size    = image_size;
+        (*pcb).pid           = self.next_pid;
+        (*pcb).tgid          = (*pcb).pid;
+        (*pcb).vruntime      = 0;
+        (*pcb).pc            = ip;
+        (*pcb).kill          = false;
+        (*pcb).zombie        = false;
+        (*pcb).exit_status   = 0;
+        (*pcb).children      = core::ptr::null::<PCB>() as *mut PCB;
+        (*pcb).sibling       = core::ptr::null::<PCB>() as *mut PCB;
+        (*pcb).parent        = core::ptr::null::<PCB>() as *mut PCB;
+        if !self.current.is_null() && self.current != self.idle {
+            scheduler::adopt(self.current, pcb);
+        }
+        (*pcb).QM            = QM;
+        (*pcb).mem_limit     = mem_limit;
+        (*pcb).waitpid       = -1;
+        (*pcb).join_tid      = -1;
+        (*pcb).sleep         = 0;
+        (*pcb).set_name(name);
+        (*pcb).class         = SchedClass::Fair;
+        (*pcb).dl_runtime      = 0;
+        (*pcb).dl_period       = 0;
+        (*pcb).dl_deadline     = 0;
+        (*pcb).dl_abs_deadline = 0;
+        (*pcb).dl_release      = 0;
+        (*pcb).dl_budget       = 0;
+        (*pcb).dl_misses       = 0;
+        (*pcb).wake_stamp      = read_mtime();
+        loader::push_args(pcb, args);
+
+        if !(*self.procs).insert((*pcb).pid, pcb) || !self.policy.admit(pcb) {
+            println!("could not queue the new process!");
+            /* The image is still the caller's to free */
+            (*pcb).image = core::ptr::null_mut();
+            self.reap(pcb);
+            self.oom();
+            return -1;
+        }
+        self.next_pid += 1;
+        
+        println!("new_process(): new pid = {}", (*pcb).pid);
+
+        return (*pcb).pid;
     }
 
-    pub unsafe fn collect_all_procs(&mut self) -> *mut *mut PCB {
-        let mut n = (*self.schedule).len + 1;
-        if !self.current.is_null() {
-            n += 1;
+    fn dl_utilisation(runtime: u32, period: u32) -> u32 {
+        return ((runtime as u64 * DL_UTIL_SCALE as u64) / period as u64) as u32;
+    }
+
+    /* Move the current process into the deadline class. It asks for `runtime`
+     * ticks of CPU every `period` ticks, finished within `deadline` ticks of
+     * the start of each period. The request is refused if it would push the
+     * total deadline utilisation past 100%.
+     * Returns 0 on success, 1 for bad parameters, 2 if admission fails. */
+    pub unsafe fn set_deadline(&mut self, runtime: u32, period: u32, deadline: u32) -> u32 {
+        if self.current.is_null() {
+            return 1;
+        }
+        if runtime == 0 || runtime > deadline || deadline > period {
+            return 1;
         }
 
-        let array = kmalloc(n as u32 * core::mem::size_of::<*mut PCB>() as u32) as *mut *mut PCB;
+        let pcb = self.current;
+        let util = scheduler::dl_utilisation(runtime, period);
+        let mut total = self.dl_util;
+        if (*pcb).class == SchedClass::Deadline {
+            total -= scheduler::dl_utilisation((*pcb).dl_runtime, (*pcb).dl_period);
+        }
+        if total + util > DL_UTIL_SCALE {
+            return 2;
+        }
 
-        let mut idx = 0;
-        if !self.current.is_null() {
-            *array.offset(0) = self.current;
-            idx += 1;
+        /* The caller is running and so normally off the fair queue, but
+         * make sure it can't be picked from there too */
+        if (*pcb).class == SchedClass::Fair {
+            self.policy.dequeue(pcb);
         }
+        self.dl_util           = total + util;
+        (*pcb).class           = SchedClass::Deadline;
+        (*pcb).dl_runtime      = runtime;
+        (*pcb).dl_period       = period;
+        (*pcb).dl_deadline     = deadline;
+        (*pcb).dl_budget       = runtime;
+        (*pcb).dl_abs_deadline = self.ticks + deadline;
+        (*pcb).dl_release      = self.ticks + period;
 
-        scheduler::_collect_all_procs((*self.schedule).root, array, &mut idx);
+        return 0;
+    }
 
-        *array.offset(idx as isize) = core::ptr::null::<PCB>() as *mut PCB;
+    pub unsafe fn has_pid(&mut self, pid : i32) -> bool {
+        return (*self.procs).lookup(pid).is_some();
+    }
 
-        return array;
+    /* Find a process by PID, whether it is running, runnable or blocked. */
+    pub unsafe fn get_pcb(&mut self, pid : i32) -> *mut PCB {
+        return match (*self.procs).lookup(pid) {
+            Some((_, pcb)) => *pcb,
+            None           => core::ptr::null::<PCB>() as *mut PCB,
+        };
     }
 
-    unsafe fn array_of_pcbs_has_pid(array : *mut *mut PCB, pid : i32) -> bool {
-        let mut ptr = array;
+    pub unsafe fn n_procs(&self) -> usize {
+        return (*self.procs).len;
+    }
 
-        while !(*ptr).is_null() {
-            if (*(*ptr)).pid == pid {
-                return true;
-            }
-            ptr = ptr.offset(1);
+    pub unsafe fn collect_all_procs(&mut self) -> *mut *mut PCB {
+        let n = self.n_procs() + 1;
+
+        let array = kmalloc(n as u32 * core::mem::size_of::<*mut PCB>() as u32) as *mut *mut PCB;
+        if array.is_null() {
+            return array;
         }
 
-        return false;
+        let mut idx = 0;
+        (*self.procs).for_each(|_, pcb| {
+            *array.offset(idx as isize) = *pcb;
+            idx += 1;
+        });
+
+        *array.offset(idx as isize) = core::ptr::null::<PCB>() as *mut PCB;
+
+        return array;
     }
 }
 
+/* Where _proc_entry sends a new process the first time it is switched to.
+ * Returns the pc to start it at. */
+#[no_mangle]
+pub unsafe extern "C" fn proc_first_run() -> u32 {
+    sched.switched_in();
+    return sched.trap_exit();
+}
+
 pub fn reset_timers() {
 
     let mtimelo        : &mut u32 = get_clint_register(ClintRegister :: MTIMELO);
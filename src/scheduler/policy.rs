@@ -0,0 +1,152 @@
+/* policy.rs
+ *
+ * The interface between the scheduler core and the policy that orders
+ * fair-class processes. The core owns process state (sleeping, waiting,
+ * killed, deadline class); a policy only decides which runnable process
+ * goes next and how running time is charged.
+ *
+ * The policy is picked at build time:
+ *   default         vruntime tree (cfs.rs)
+ *   sched_rr        round robin (rr.rs)
+ *   sched_lottery   lottery scheduling (lottery.rs)
+ *   sched_stride    stride scheduling (stride.rs)
+ *
+ * (make SCHED=rr, SCHED=lottery or SCHED=stride.)
+ *
+ * A process's QM means the same under every policy: it is how heavily its
+ * running time is weighed, so a larger QM gets a smaller share of the CPU.
+ * CFS adds it to vruntime directly; the ticket-based policies turn it into
+ * tickets with tickets_of().
+ */
+
+use crate::scheduler::PCB;
+use crate::utils::rbtree::{ rbtree, rbtree_node };
+use crate::mem::slab::ObjectCache;
+use crate::console;
+use core::fmt::Write;
+
+pub trait SchedPolicy {
+    /* Set up any heap state. Called once from scheduler::init(). */
+    fn init(&mut self);
+
+    /* A brand new process. Policies can seed their per-process state here.
+     * Returns false if it could not be queued. */
+    unsafe fn admit(&mut self, pcb: *mut PCB) -> bool {
+        return self.enqueue(pcb);
+    }
+
+    /* Make a process eligible to be picked. Returns false if there was no
+     * memory to queue it. */
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool;
+
+    /* Remove a process without picking it. Returns false if it wasn't queued. */
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool;
+
+    /* Remove and return the process that should run next, or null. */
+    unsafe fn pick_next(&mut self) -> *mut PCB;
+
+    /* The running process used up its slice. */
+    unsafe fn tick(&mut self, pcb: *mut PCB);
+
+    /* The running process gave the CPU up before its slice was over. */
+    unsafe fn yield_now(&mut self, pcb: *mut PCB);
+
+    fn len(&self) -> usize;
+}
+
+#[cfg(any(all(feature="sched_rr", feature="sched_lottery"),
+          all(feature="sched_rr", feature="sched_stride"),
+          all(feature="sched_lottery", feature="sched_stride")))]
+compile_error!("pick at most one of sched_rr, sched_lottery and sched_stride");
+
+#[cfg(not(any(feature="sched_rr", feature="sched_lottery", feature="sched_stride")))]
+pub type Policy = crate::scheduler::cfs::CFS;
+
+#[cfg(feature="sched_rr")]
+pub type Policy = crate::scheduler::rr::RoundRobin;
+
+#[cfg(feature="sched_lottery")]
+pub type Policy = crate::scheduler::lottery::Lottery;
+
+#[cfg(feature="sched_stride")]
+pub type Policy = crate::scheduler::stride::Stride;
+
+/* Tickets held by a QM 1 process; QM q holds 1/q as many */
+pub const TICKETS : u32 = 1 << 10;
+
+/* QM converted to tickets for lottery and stride, never fewer than one. */
+pub fn tickets_of(pcb: *mut PCB) -> u32 {
+    let qm = unsafe { (*pcb).QM };
+    return core::cmp::max(TICKETS / core::cmp::max(qm, 1), 1);
+}
+
+/* Helpers shared by the rbtree-backed policies. */
+
+/* Nodes for every tree made by new_tree. Processes move between these
+ * trees on every switch, so their nodes are cached rather than boxed. */
+pub static mut TREE_NODES: ObjectCache<rbtree_node<u32, *mut PCB>> = ObjectCache::new("tree nodes", 16, None);
+/* and the trees themselves: the policy's and the core's deadline trees */
+pub static mut TREES: ObjectCache<rbtree<u32, *mut PCB>> = ObjectCache::new("trees", 4, None);
+
+/* Trees are only made at boot, so running out of heap here is fatal */
+pub fn new_tree() -> *mut rbtree<u32, *mut PCB> {
+    unsafe {
+        let tree = TREES.alloc();
+        if tree.is_null() {
+            println!("could not allocate a run queue!");
+            crate::abort();
+        }
+        core::ptr::write(tree, rbtree::with_cache(&mut TREE_NODES));
+        return tree;
+    }
+}
+
+/* Keys must be unique, so bump the key until there's a free slot. Returns
+ * false if there is no node for it. */
+pub unsafe fn add_to_tree(tree : *mut rbtree<u32, *mut PCB>, mut key : u32, val : *mut PCB) -> bool {
+    loop {
+        if let None = (*tree).lookup(key) {
+            return (*tree).insert(key, val);
+        }
+
+        key = key.wrapping_add(1);
+    }
+}
+
+unsafe fn _tree_key_of(node : *mut rbtree_node<u32, *mut PCB>, pcb : *mut PCB) -> Option<u32> {
+    if node.is_null() { return None; }
+    if (*node).val == pcb { return Some((*node).key); }
+
+    if let Some(k) = _tree_key_of((*node).children[0], pcb) {
+        return Some(k);
+    }
+    return _tree_key_of((*node).children[1], pcb);
+}
+
+/* Remove a process from a tree, wherever it is. */
+pub unsafe fn remove_from_tree(tree : *mut rbtree<u32, *mut PCB>, pcb : *mut PCB) -> bool {
+    match _tree_key_of((*tree).root, pcb) {
+        Some(key) => (*tree).delete(key),
+        None      => false,
+    }
+}
+
+/* Take `delta` off every key in the tree. Only for a delta no bigger than
+ * the smallest key, so the order doesn't change. */
+pub unsafe fn tree_shift_keys(node : *mut rbtree_node<u32, *mut PCB>, delta : u32) {
+    if node.is_null() { return; }
+
+    (*node).key -= delta;
+    tree_shift_keys((*node).children[0], delta);
+    tree_shift_keys((*node).children[1], delta);
+}
+
+/* Pop the node with the lowest key. */
+pub unsafe fn tree_pop_first(tree : *mut rbtree<u32, *mut PCB>) -> *mut PCB {
+    if let Some((key, pcb)) = (*tree).first() {
+        let pcb = *pcb;
+        (*tree).delete(*key);
+        return pcb;
+    }
+    return core::ptr::null::<PCB>() as *mut PCB;
+}
@@ -0,0 +1,65 @@
+/* rr.rs
+ *
+ * Round robin. The run queue is an rbtree keyed on an ever-increasing
+ * sequence number, so the first node is always the head of the queue and
+ * enqueueing puts a process at the back. Before the sequence number can
+ * wrap, every key is moved down so the head is at 0 again.
+ */
+
+use crate::scheduler::PCB;
+use crate::scheduler::policy::{*};
+use crate::utils::rbtree::rbtree;
+
+const REBASE_AT : u32 = 1 << 31;
+
+pub struct RoundRobin {
+    tree: *mut rbtree<u32, *mut PCB>,
+    seq : u32,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        RoundRobin {
+            tree: core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            seq : 0,
+        }
+    }
+}
+
+impl SchedPolicy for RoundRobin {
+    fn init(&mut self) {
+        self.tree = new_tree();
+    }
+
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool {
+        if self.seq >= REBASE_AT {
+            let head = match (*self.tree).first() {
+                Some((key, _)) => *key,
+                None           => self.seq,
+            };
+            tree_shift_keys((*self.tree).root, head);
+            self.seq -= head;
+        }
+        if !add_to_tree(self.tree, self.seq, pcb) {
+            return false;
+        }
+        self.seq += 1;
+        return true;
+    }
+
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool {
+        return remove_from_tree(self.tree, pcb);
+    }
+
+    unsafe fn pick_next(&mut self) -> *mut PCB {
+        return tree_pop_first(self.tree);
+    }
+
+    unsafe fn tick(&mut self, _pcb: *mut PCB) {}
+
+    unsafe fn yield_now(&mut self, _pcb: *mut PCB) {}
+
+    fn len(&self) -> usize {
+        unsafe { (*self.tree).len }
+    }
+}
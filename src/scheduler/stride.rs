@@ -0,0 +1,96 @@
+/* stride.rs
+ *
+ * Stride scheduling. Each process has a stride of STRIDE1 / tickets (see
+ * tickets_of) and a pass value, kept in PCB::vruntime. The lowest pass runs
+ * next and has its stride added for every tick it runs. A new process
+ * starts at the lowest pass in the queue so it can't starve the others.
+ *
+ * Passes only ever grow, so they are kept as offsets from `floor`. When the
+ * lowest pass gets far enough from it, floor moves up to meet it and every
+ * queued key comes down by the same amount. A process that was off the
+ * queue while that happened, or slept so long it fell behind floor, comes
+ * back at floor.
+ */
+
+use crate::scheduler::PCB;
+use crate::scheduler::policy::{*};
+use crate::utils::rbtree::rbtree;
+
+const STRIDE1   : u32 = 1 << 16;
+const REBASE_AT : u32 = 1 << 30;
+
+pub struct Stride {
+    tree : *mut rbtree<u32, *mut PCB>,
+    floor: u32,
+}
+
+impl Stride {
+    pub const fn new() -> Self {
+        Stride {
+            tree : core::ptr::null::<rbtree<u32, *mut PCB>>() as *mut rbtree<u32, *mut PCB>,
+            floor: 0,
+        }
+    }
+
+    fn stride_of(pcb: *mut PCB) -> u32 {
+        return STRIDE1 / tickets_of(pcb);
+    }
+
+    /* Where `pcb` goes in the tree. Queued passes never get further than
+     * REBASE_AT plus a stride or two ahead of floor, so anything past twice
+     * that has wrapped round from behind it. */
+    unsafe fn key_of(&mut self, pcb: *mut PCB) -> u32 {
+        if (*pcb).vruntime.wrapping_sub(self.floor) >= 2 * REBASE_AT {
+            (*pcb).vruntime = self.floor;
+        }
+        return (*pcb).vruntime.wrapping_sub(self.floor);
+    }
+}
+
+impl SchedPolicy for Stride {
+    fn init(&mut self) {
+        self.tree = new_tree();
+    }
+
+    unsafe fn admit(&mut self, pcb: *mut PCB) -> bool {
+        (*pcb).vruntime = match (*self.tree).first() {
+            Some((key, _)) => self.floor.wrapping_add(*key),
+            None           => self.floor,
+        };
+        return self.enqueue(pcb);
+    }
+
+    unsafe fn enqueue(&mut self, pcb: *mut PCB) -> bool {
+        let key = self.key_of(pcb);
+        return add_to_tree(self.tree, key, pcb);
+    }
+
+    unsafe fn dequeue(&mut self, pcb: *mut PCB) -> bool {
+        return remove_from_tree(self.tree, pcb);
+    }
+
+    unsafe fn pick_next(&mut self) -> *mut PCB {
+        let lowest = match (*self.tree).first() {
+            Some((key, _)) => *key,
+            None           => return core::ptr::null::<PCB>() as *mut PCB,
+        };
+        if lowest >= REBASE_AT {
+            tree_shift_keys((*self.tree).root, lowest);
+            self.floor = self.floor.wrapping_add(lowest);
+        }
+        return tree_pop_first(self.tree);
+    }
+
+    unsafe fn tick(&mut self, pcb: *mut PCB) {
+        (*pcb).vruntime = (*pcb).vruntime.wrapping_add(Stride::stride_of(pcb));
+    }
+
+    /* Only charge for the part of the slice that was used: half a stride. */
+    unsafe fn yield_now(&mut self, pcb: *mut PCB) {
+        (*pcb).vruntime = (*pcb).vruntime.wrapping_add(Stride::stride_of(pcb) / 2);
+    }
+
+    fn len(&self) -> usize {
+        unsafe { (*self.tree).len }
+    }
+}
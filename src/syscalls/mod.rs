@@ -5,6 +5,8 @@ use crate::console as console;
 use crate::machine_info::{*};
 use crate::scheduler::{*};
 use crate::mem::heap::{*};
+use crate::loader;
+use crate::loader::elf::LoadError;
 use core::fmt::Write;
 
 extern "C" {
@@ -16,8 +18,6 @@ extern "C" {
 pub const EXIT:     u32 = 1;
 pub const WRITE:    u32 = 2;
 pub const READ:     u32 = 3;
-pub const ALLOC:    u32 = 4;
-pub const FREE:     u32 = 5;
 pub const BARRIER:  u32 = 6;
 pub const SPAWN:    u32 = 7;
 pub const WAITPID:  u32 = 8;
@@ -25,32 +25,125 @@ pub const KILL:     u32 = 9;
 pub const NPROC:    u32 = 10;
 pub const PROCS:    u32 = 11;
 pub const SLEEP:    u32 = 12;
+pub const SETDEADLINE: u32 = 13;
+pub const GETRUSAGE: u32 = 14;
+pub const SYSUSAGE: u32 = 15;
+pub const SETNAME:  u32 = 16;
+pub const GETNAME:  u32 = 17;
+pub const SPAWN_PATH: u32 = 18;
+pub const SPAWN_FLASH: u32 = 19;
+pub const THREAD_CREATE: u32 = 20;
+pub const THREAD_JOIN: u32 = 21;
+pub const THREAD_EXIT: u32 = 22;
+pub const BRK:      u32 = 23;
+pub const MEMINFO:  u32 = 24;
+
+/* WAITPID target meaning "any of my children" */
+pub const WAIT_ANY: i32 = -2;
+
+/* Wait channel for processes blocked in READ on the console */
+pub const WCHAN_CONSOLE: u32 = 1;
 
 pub const UMODE:    u32 = 0;
 pub const MMODE:    u32 = 3;
 
 pub struct process_info {
     pub pid      : i32,
+    pub tgid     : i32,
     pub vruntime : u32,
-    pub name     : *const char,
+    pub name     : [u8; PROC_NAME_LEN],
     pub waitpid  : i32,
-    pub sleep    : i16
+    pub sleep    : i16,
+    pub ppid     : i32,
+    pub zombie   : bool,
+    pub class    : SchedClass,
+    pub dl_misses: u32,
+    pub utime    : u64,
+    pub ktime    : u64,
+    pub last_run : u64,
+    /* Bytes in the thread group's BRK arena, and in all, counting stacks and
+     * the program image. Shared by every thread in the group. */
+    pub heap     : u32,
+    pub mem      : u32
+}
+
+/* A string handed to the kernel by pointer and length. */
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct user_str {
+    pub ptr : *const u8,
+    pub len : u32
+}
+
+/* Arguments for a new process. `envp` holds `envc` (key, value) pairs, so
+ * it is 2 * envc entries long. */
+#[repr(C)]
+pub struct proc_args {
+    pub argv : *const user_str,
+    pub argc : u32,
+    pub envp : *const user_str,
+    pub envc : u32
+}
+
+/* Per-process accounting returned by GETRUSAGE. Times are in mtime ticks. */
+#[repr(C)]
+pub struct rusage {
+    pub utime              : u64,
+    pub ktime              : u64,
+    pub last_run           : u64,
+    pub wake_latency_total : u64,
+    pub wake_latency_max   : u64,
+    pub wakeups            : u32,
+    pub nvcsw              : u32,
+    pub nivcsw             : u32,
+    pub dl_misses          : u32,
+    /* Same as process_info.heap and .mem */
+    pub heap               : u32,
+    pub mem                : u32
+}
+
+/* System-wide accounting returned by SYSUSAGE. Times are in mtime ticks. */
+#[repr(C)]
+pub struct sysusage {
+    pub uptime    : u64,
+    pub idle_time : u64,
+    pub irq_time  : u64,
+    pub switches  : u32,
+    pub nprocs    : u32,
+    /* Traps taken, and mcycle cycles spent in them and in context switches */
+    pub traps         : u32,
+    pub trap_cycles   : u64,
+    pub switch_cycles : u64
+}
+
+/* Memory use returned by MEMINFO. Sizes are in bytes, fragmentation is per
+ * mille: how much of the free memory lies outside the largest free block. */
+#[repr(C)]
+pub struct meminfo {
+    /* The kernel heap */
+    pub total         : u32,
+    pub free          : u32,
+    pub largest_free  : u32,
+    pub blocks        : u32,
+    pub free_blocks   : u32,
+    pub fragmentation : u32,
+    /* Allocations the kernel heap has turned down */
+    pub failures      : u32,
+    /* User memory, where stacks, images and arenas live */
+    pub user_total         : u32,
+    pub user_free          : u32,
+    pub user_largest_free  : u32,
+    pub user_fragmentation : u32
 }
 
 extern "C" {
     fn ecall_wrapper(code : u32, arg0 : u32, arg1 : u32, arg2 : u32, arg3 : u32, arg4 : u32, arg5 : u32) -> u32;
 }
 
+/* Blocking calls (EXIT, WAITPID, SLEEP) don't return until the kernel has
+ * something to hand back; EXIT never returns. */
 pub unsafe fn syscall(code : u32, arg0 : u32, arg1 : u32, arg2 : u32, arg3 : u32, arg4 : u32, arg5 : u32) -> u32 {
-    let result = ecall_wrapper(code, arg0, arg1, arg2, arg3, arg4, arg5);
-
-    match code {
-        EXIT            => loop { asm!("wfi") },
-        WAITPID | SLEEP => asm!("wfi"),
-        _               => {}
-    };
-
-    return result
+    return ecall_wrapper(code, arg0, arg1, arg2, arg3, arg4, arg5);
 }
 
 pub fn do_msyscall (code: u32) {
@@ -65,69 +158,205 @@ pub fn do_msyscall (code: u32) {
 pub unsafe fn do_syscall (code: u32, arg0 : u32, arg1 : u32, arg2 : u32, arg3 : u32, arg4 : u32, arg5 : u32) -> u32 {
     let mut result = 0;
     match code {
-        EXIT    => result = handle_exit(),
+        EXIT    => result = handle_exit(arg0 as i32),
         WRITE   => println!("SYSCALL WRITE"),
-        READ    => println!("SYSCALL READ"),
-        ALLOC   => result = handle_alloc(arg0),
-        FREE    => result = handle_free(arg0),
+        READ    => result = handle_read(arg0, arg1),
         BARRIER => println!("SYSCALL BARRIER"),
         SPAWN   => result = handle_spawn(arg0, arg1, arg2, arg3, arg4, arg5),
-        WAITPID => result = handle_waitpid(arg0),
+        WAITPID => result = handle_waitpid(arg0 as i32, arg1),
         KILL    => result = handle_kill(arg0),
         NPROC   => result = handle_nproc(),
-        PROCS   => result = handle_procs(arg0),
+        PROCS   => result = handle_procs(arg0, arg1),
         SLEEP   => result = handle_sleep(arg0),
+        SETDEADLINE => result = handle_setdeadline(arg0, arg1, arg2),
+        GETRUSAGE => result = handle_getrusage(arg0 as i32, arg1),
+        SETNAME => result = handle_setname(arg0, arg1),
+        GETNAME => result = handle_getname(arg0 as i32, arg1, arg2),
+        SPAWN_PATH => result = handle_spawn_path(arg0, arg1, arg2, arg3, arg4, arg5),
+        SPAWN_FLASH => result = handle_spawn_flash(arg0, arg1, arg2, arg3, arg4, arg5),
+        THREAD_CREATE => result = handle_thread_create(arg0, arg1, arg2, arg3),
+        THREAD_JOIN => result = handle_thread_join(arg0 as i32, arg1),
+        THREAD_EXIT => result = handle_thread_exit(arg0 as i32),
+        BRK     => result = handle_brk(arg0, arg1),
+        MEMINFO => result = handle_meminfo(arg0, arg1),
+        SYSUSAGE  => result = handle_sysusage(arg0),
         _       => println!("Unknown User Mode ECALL CODE"),
     };
     return result;
 }
 
-unsafe fn handle_exit() -> u32 {
+/* Exits the whole process, every thread included. */
+unsafe fn handle_exit(status : i32) -> u32 {
     if sched.current.is_null() {
         return 1;
     }
 
-    (*sched.current).kill = true;
+    sched.exit_group(status);
     return 0;
 }
 
-unsafe fn handle_alloc(n_bytes : u32) -> u32 {
-    return kmalloc(n_bytes) as u32;
+/* Read up to `len` bytes of console input into `buf`, sleeping until at
+ * least one byte is available. Returns the number of bytes read. */
+unsafe fn handle_read(buf : u32, len : u32) -> u32 {
+    if buf == 0 {
+        return 0;
+    }
+
+    let buf = buf as *mut u8;
+    let mut n = 0;
+    loop {
+        while n < len {
+            match console::getc() {
+                Some(c) => *buf.offset(n as isize) = c as u8,
+                None    => break,
+            }
+            n += 1;
+        }
+        if n > 0 || len == 0 || sched.current.is_null() {
+            return n;
+        }
+        sched.sleep_on(WCHAN_CONSOLE);
+    }
 }
-unsafe fn handle_free(ptr : u32) -> u32 {
-    kfree(ptr as *mut u32);
-    return 0;
+/* Move the caller's break to `addr` (0 to leave it), storing the base of
+ * its arena at `base` if that isn't null. Returns the break, which is only
+ * where it was asked to be if the move worked. */
+unsafe fn handle_brk(addr : u32, base : u32) -> u32 {
+    let brk = sched.brk(addr);
+    if base != 0 && !sched.current.is_null() {
+        let leader = scheduler::group_leader(sched.current);
+        *(base as *mut u32) = (*leader).arena as u32;
+    }
+    return brk;
+}
+/* `args` points at a proc_args block (or is null). `name` points at a
+ * NUL-terminated string, read up to the NUL and no further than
+ * PROC_NAME_LEN bytes. The child may hold `mem_limit` bytes of heap, or
+ * whatever the caller has left if that is 0, and never more than that. */
+unsafe fn handle_spawn(stack_size : u32, ip : u32, QM : u32, args : u32, name : u32, mem_limit : u32) -> u32 {
+    let name = if name == 0 {
+        &[][..]
+    } else {
+        let name = name as *const u8;
+        let mut len = 0;
+        while len < PROC_NAME_LEN && *name.offset(len as isize) != 0 {
+            len += 1;
+        }
+        core::slice::from_raw_parts(name, len)
+    };
+    return sched.new_process(stack_size, ip, QM, args as *const proc_args, name, mem_limit, core::ptr::null_mut(), 0) as u32
 }
-unsafe fn handle_spawn(stack_size : u32, ip : u32, QM : u32, data : u32, data_len : u32, name : u32) -> u32 {
-    return sched.new_process(stack_size, ip, QM, data as *mut u32, data_len, name as *mut char) as u32
+/* Start the ELF executable at `path`. Returns the new PID, or one of the
+ * negative LoadError codes. */
+unsafe fn handle_spawn_path(path : u32, path_len : u32, args : u32, stack_size : u32, QM : u32, mem_limit : u32) -> u32 {
+    if path == 0 {
+        return LoadError::NotFound.code() as u32;
+    }
+    let path = core::slice::from_raw_parts(path as *const u8, path_len as usize);
+    return match loader::spawn_path(path, args as *const proc_args, stack_size, QM, mem_limit) {
+        Ok(pid) => pid as u32,
+        Err(e)  => e.code() as u32,
+    };
 }
-unsafe fn handle_waitpid(pid : u32) -> u32 {
-    if sched.current.is_null()
-    || !sched.tree_has_pid(pid as i32) {
+/* Start an instance of the execute-in-place program `name`. Returns the new
+ * PID, or one of the negative LoadError codes. */
+unsafe fn handle_spawn_flash(name : u32, name_len : u32, args : u32, stack_size : u32, QM : u32, mem_limit : u32) -> u32 {
+    if name == 0 {
+        return LoadError::NotFound.code() as u32;
+    }
+    let name = core::slice::from_raw_parts(name as *const u8, name_len as usize);
+    return match loader::xip::spawn_flash(name, args as *const proc_args, stack_size, QM, mem_limit) {
+        Ok(pid) => pid as u32,
+        Err(e)  => e.code() as u32,
+    };
+}
+/* Start a thread in the caller's process at `ip`, with a0 = arg0 and
+ * a1 = arg1. Returns the thread ID, or -1. */
+unsafe fn handle_thread_create(ip : u32, arg0 : u32, arg1 : u32, stack_size : u32) -> u32 {
+    return sched.new_thread(stack_size, ip, arg0, arg1) as u32;
+}
+/* Wait for thread `tid` of the caller's process to exit and reap it,
+ * storing its exit status at `status` if that isn't null. Returns 0, or -1
+ * if there is no such thread. */
+unsafe fn handle_thread_join(tid : i32, status : u32) -> u32 {
+    let pcb = sched.current;
+    if pcb.is_null() {
+        return -1i32 as u32;
+    }
+
+    let thread = sched.get_pcb(tid);
+    if thread.is_null() || thread == pcb || (*thread).leader.is_null()
+       || (*thread).tgid != (*pcb).tgid {
+        return -1i32 as u32;
+    }
+
+    (*pcb).join_tid    = tid;
+    (*pcb).wait_status = status;
+    if !(*thread).zombie {
+        return 0;
+    }
+    return sched.finish_join(pcb) as u32;
+}
+/* End the calling thread. For the thread that started the process this is
+ * the same as EXIT. */
+unsafe fn handle_thread_exit(status : i32) -> u32 {
+    let pcb = sched.current;
+    if pcb.is_null() {
         return 1;
     }
-    (*sched.current).waitpid = pid as i32;
+
+    if (*pcb).leader.is_null() {
+        sched.exit_group(status);
+    } else {
+        sched.exit(pcb, status);
+    }
     return 0;
 }
+/* Reap a zombie child (`pid`, or any child for WAIT_ANY) and return its
+ * PID, storing its exit status at `status` if that isn't null. Returns -1
+ * if there is no such child. If the child is still running the caller
+ * blocks, and the scheduler finishes the call once the child exits. */
+unsafe fn handle_waitpid(pid : i32, status : u32) -> u32 {
+    let pcb = sched.current;
+    if pcb.is_null() {
+        return -1i32 as u32;
+    }
+
+    (*pcb).waitpid     = pid;
+    (*pcb).wait_status = status;
+
+    let mut has_child = false;
+    let child = sched.find_zombie_child(pcb, pid, &mut has_child);
+    if child.is_null() && has_child {
+        return 0;
+    }
+    return sched.finish_wait(pcb) as u32;
+}
+/* Init can't be killed; everything else would be orphaned with it. */
 unsafe fn handle_kill(pid : u32) -> u32 {
+    if pid as i32 == INIT_PID {
+        return 1;
+    }
     let pcb = sched.get_pcb(pid as i32);
-    if pcb.is_null() {
+    if pcb.is_null() || (*pcb).zombie {
         return 1;
     }
-    (*pcb).kill = true;
+    sched.kill(pcb, EXIT_KILLED);
     return 0;
 }
 unsafe fn handle_nproc() -> u32 {
-    let mut n_in_tree = (*sched.schedule).len;
-    if !sched.current.is_null() {
-        n_in_tree += 1;
-    }
-    return n_in_tree as u32;
+    return sched.n_procs() as u32;
 }
-unsafe fn handle_procs(out : u32) -> u32 {
+/* Fill `out` with the details of up to `max` processes. Returns how many
+ * it filled in. */
+unsafe fn handle_procs(out : u32, max : u32) -> u32 {
+    let out = out as *mut process_info;
     let all_pcbs = sched.collect_all_procs();
-    if all_pcbs.is_null() {
-        return 1;
+    if out.is_null() || all_pcbs.is_null() {
+        if !all_pcbs.is_null() {
+            kfree(all_pcbs as *mut u32);
+        }
+        return 0;
     }
    
     let mut n = 0;
@@ -137,37 +366,136 @@ unsafe fn handle_procs(out : u32) -> u32 {
         ptr = ptr.offset(1);
     }
    
-    let proc_infos = kmalloc(n * core::mem::size_of::<process_info>() as u32) as *mut process_info;
-
-    if proc_infos.is_null() {
-        println!("Could not allocate process_info array!");
-    }
-
+    let n = core::cmp::min(n, max);
     for i in 0..n {
         let pcb : &PCB = &**all_pcbs.offset(i as isize);
         let info = process_info {
             pid      : pcb.pid,
+            tgid     : pcb.tgid,
             vruntime : pcb.vruntime,
             name     : pcb.name,
             waitpid  : pcb.waitpid,
-            sleep    : pcb.sleep
+            sleep    : pcb.sleep,
+            ppid     : if pcb.parent.is_null() { -1 } else { (*pcb.parent).pid },
+            zombie   : pcb.zombie,
+            class    : pcb.class,
+            dl_misses: pcb.dl_misses,
+            utime    : pcb.utime,
+            ktime    : pcb.ktime,
+            last_run : pcb.last_run,
+            heap     : (*scheduler::group_leader(pcb as *const PCB as *mut PCB)).arena_size,
+            mem      : scheduler::mem_usage(scheduler::group_leader(pcb as *const PCB as *mut PCB))
         };
 
-        *proc_infos.offset(i as isize) = info;
+        *out.offset(i as isize) = info;
     }
-
-    let out_ptr = out as *mut *const process_info;
-    *out_ptr = proc_infos;
+    kfree(all_pcbs as *mut u32);
 
     return n;
 }
+/* Sleep for `secs` seconds of 100 ticks each. PCB::sleep is an i16, so
+ * longer sleeps are cut to the most it can count. */
 unsafe fn handle_sleep(secs : u32) -> u32 {
     if sched.current.is_null() {
         return 1;
     }
+    let secs = core::cmp::min(secs, core::i16::MAX as u32 / 100);
     (*sched.current).sleep = (100 * secs) as i16;
     return 0;
 }
+unsafe fn handle_setdeadline(runtime : u32, period : u32, deadline : u32) -> u32 {
+    return sched.set_deadline(runtime, period, deadline);
+}
+/* Fill `out` with the accounting for `pid`, or the caller if pid is -1. */
+unsafe fn handle_getrusage(pid : i32, out : u32) -> u32 {
+    let pcb = if pid == -1 { sched.current } else { sched.get_pcb(pid) };
+    if pcb.is_null() || out == 0 {
+        return 1;
+    }
+
+    *(out as *mut rusage) = rusage {
+        utime              : (*pcb).utime,
+        ktime              : (*pcb).ktime,
+        last_run           : (*pcb).last_run,
+        wake_latency_total : (*pcb).wake_latency_total,
+        wake_latency_max   : (*pcb).wake_latency_max,
+        wakeups            : (*pcb).wakeups,
+        nvcsw              : (*pcb).nvcsw,
+        nivcsw             : (*pcb).nivcsw,
+        dl_misses          : (*pcb).dl_misses,
+        heap               : (*scheduler::group_leader(pcb)).arena_size,
+        mem                : scheduler::mem_usage(scheduler::group_leader(pcb))
+    };
+    return 0;
+}
+unsafe fn handle_sysusage(out : u32) -> u32 {
+    if out == 0 {
+        return 1;
+    }
+
+    *(out as *mut sysusage) = sysusage {
+        uptime    : read_mtime() - sched.boot_time,
+        idle_time : sched.idle_time,
+        irq_time  : sched.irq_time,
+        switches  : sched.switches,
+        nprocs    : sched.n_procs() as u32,
+        traps         : sched.traps,
+        trap_cycles   : sched.trap_cycle_total,
+        switch_cycles : sched.switch_cycles
+    };
+    return 0;
+}
+/* Fill in `out` with the state of kernel and user memory, and print a map
+ * of both to the console first if `dump` is set. */
+unsafe fn handle_meminfo(out : u32, dump : u32) -> u32 {
+    if out == 0 {
+        return 1;
+    }
+
+    if dump != 0 {
+        heap_dump();
+        crate::mem::user::user_dump();
+    }
+    let heap = heap_stats();
+    let user = crate::mem::user::user_stats();
+    *(out as *mut meminfo) = meminfo {
+        total         : heap.size,
+        free          : heap.free,
+        largest_free  : heap.largest_free,
+        blocks        : heap.blocks,
+        free_blocks   : heap.free_blocks,
+        fragmentation : heap.fragmentation,
+        failures      : heap.failures,
+        user_total         : user.size,
+        user_free          : user.free,
+        user_largest_free  : user.largest_free,
+        user_fragmentation : if user.free == 0 { 0 } else { 1000 - user.largest_free * 1000 / user.free }
+    };
+    return 0;
+}
+unsafe fn handle_setname(name : u32, len : u32) -> u32 {
+    if sched.current.is_null() || name == 0 {
+        return 1;
+    }
+
+    let len = core::cmp::min(len as usize, PROC_NAME_LEN);
+    (*sched.current).set_name(core::slice::from_raw_parts(name as *const u8, len));
+    return 0;
+}
+/* Copy the name of `pid` (or the caller for -1) into `buf`, NUL terminated
+ * and truncated to `len` bytes. Returns the length of the full name. */
+unsafe fn handle_getname(pid : i32, buf : u32, len : u32) -> u32 {
+    let pcb = if pid == -1 { sched.current } else { sched.get_pcb(pid) };
+    if pcb.is_null() || buf == 0 || len == 0 {
+        return -1i32 as u32;
+    }
+
+    let name_len = (*pcb).name_len();
+    let n = core::cmp::min(name_len, len as usize - 1);
+    core::ptr::copy_nonoverlapping((*pcb).name.as_ptr(), buf as *mut u8, n);
+    *(buf as *mut u8).offset(n as isize) = 0;
+    return name_len as u32;
+}
 unsafe fn handle_mypid() -> u32 {
     if sched.current.is_null() {
         return 0xFFFFFFFF;
@@ -3,10 +3,6 @@ use core::fmt::Write;
 use crate::syscalls as syscalls;
 use crate::scheduler;
 
-extern "C" {
-  static mut GLOBAL_CTX: [u32; 32];
-}
-
 const  ASYNC      : u32 = 0x80000000;
 const  SYNC       : u32 = 0;
 const  CODE_MASK  : u32 = 0x7FFFFFFF;
@@ -41,17 +37,27 @@ struct trap_handler{}
 
 
 
+/* Runs on the kernel stack of whichever process trapped. The handler may
+ * switch to another process, so the pc returned is that of whoever is
+ * current by the time we get back here. */
 #[no_mangle]
-fn handle_trap(cause: u32, mut mepc: u32, mtval: u32) -> u32{
+fn handle_trap(cause: u32, mepc: u32, mtval: u32) -> u32{
     let code = cause & CODE_MASK;
     let mode = cause & ASYNC;
-    mepc = trap_handler::handler(code, mepc, mode, mtval);
-    mepc = trap_handler::update_mepc(mepc, mode);
-    return mepc;
+    if trap_handler::fp_trap(code, mode) {
+        /* Run the FP instruction again now the registers are loaded */
+        unsafe{ scheduler::sched.trap_enter(mepc); }
+    } else {
+        /* Step past the trapping instruction first, so a syscall that
+         * blocks resumes after its ecall */
+        unsafe{ scheduler::sched.trap_enter(trap_handler::update_mepc(mepc, mode)); }
+        trap_handler::handler(code, mode, mtval);
+    }
+    unsafe{ return scheduler::sched.trap_exit(); }
 }
 
 impl trap_handler{
-    fn handler(code: u32, mut mepc: u32, mode: u32, mtval: u32) -> u32{
+    fn handler(code: u32, mode: u32, mtval: u32) {
         match (mode, code) {
             (ASYNC, USOFTWARE) => println!("USER MODE SOFTWARE INTERRUPT"),
             (ASYNC, SSOFTWARE) => println!("SUPERVISOR MODE SOFTWARE INTERRUPT"),
@@ -61,7 +67,9 @@ impl trap_handler{
             (ASYNC, MTIMER)    => {
            //     println!("MACHINE MODE TIMER INTERRUPT");
                 unsafe{
-                mepc = scheduler::sched.update_schedule(mepc);
+                /* There is no receive interrupt, so readers poll on each tick */
+                scheduler::sched.wakeup(syscalls::WCHAN_CONSOLE);
+                scheduler::sched.update_schedule();
                 }
             },
             (ASYNC, UEXTERNAL) => println!("USER MODE EXTERNAL INTERRUPT"),
@@ -80,15 +88,17 @@ impl trap_handler{
             {
                 println!("USER MODE ECALL");
                 unsafe{
+                    let ctx = scheduler::sched.frame();
                     let result = syscalls::do_syscall(
-                        GLOBAL_CTX[10],
-                        GLOBAL_CTX[11],
-                        GLOBAL_CTX[12],
-                        GLOBAL_CTX[13],
-                        GLOBAL_CTX[14],
-                        GLOBAL_CTX[15],
-                        GLOBAL_CTX[16]);
-                    GLOBAL_CTX[10] = result;
+                        (*ctx)[10],
+                        (*ctx)[11],
+                        (*ctx)[12],
+                        (*ctx)[13],
+                        (*ctx)[14],
+                        (*ctx)[15],
+                        (*ctx)[16]);
+                    (*ctx)[10] = result;
+                    scheduler::sched.block();
                 }
             }
             (SYNC, SECALL)     => println!("SUPERVISOR MODE ECALL"),
@@ -96,7 +106,7 @@ impl trap_handler{
             {
                 println!("MACHINE MODE ECALL");
                 unsafe{
-                    syscalls::do_msyscall(GLOBAL_CTX[10]);
+                    syscalls::do_msyscall((*scheduler::sched.frame())[10]);
                 }
             },
             (SYNC, IPAGEFAULT) => println!("INSTRUCTION PAGE FAULT"),
@@ -105,7 +115,18 @@ impl trap_handler{
             (SYNC, _)          => println!("UNKNOWN SYNCRONOUS TRAP CODE"),
             _                  => println!("UNKNOWN TRAP CODE AND MODE"),
         }
-        return mepc;
+    }
+
+    /* With the fpu feature, a process's first FP instruction after being
+     * switched in traps as illegal so its FP registers can be loaded. */
+    #[cfg(feature = "fpu")]
+    fn fp_trap(code: u32, mode: u32) -> bool {
+        return mode == SYNC && code == ILLINS && unsafe { scheduler::sched.fp_fault() };
+    }
+
+    #[cfg(not(feature = "fpu"))]
+    fn fp_trap(code: u32, mode: u32) -> bool {
+        return false;
     }
 
     fn update_mepc(mepc: u32, mode: u32) -> u32{
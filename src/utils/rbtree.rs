@@ -7,9 +7,10 @@
  * time priority functionality.
  */
 
-use crate::mem::heap::{*};
+use alloc::boxed::Box;
 use core::fmt::Write;
 use crate::console;
+use crate::mem::slab::ObjectCache;
 use core::mem::{size_of, zeroed};
 use core::ptr::null;
 use core::ops::Not;
@@ -18,12 +19,10 @@ macro_rules! not {
     ($b:expr) => ((!($b as u32)) & 0x1)
 }
 
-macro_rules! alloc {
-    ($T:ty) => (kmalloc(core::mem::size_of::<$T>() as u32) as *mut $T)
-}
-
+/* Nodes come from the tree's object cache, or are boxed if it has none,
+ * and are linked by raw pointers either way */
 macro_rules! free {
-    ($ptr:expr) => (kfree($ptr as *const _ as *mut u32))
+    ($cache:expr, $ptr:expr) => (rbtree_node::free($cache, $ptr))
 }
 
 macro_rules! child {
@@ -65,28 +64,51 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
         }
     }
 
-    unsafe fn new(key : &K_T, val : &V_T) -> *mut Self {
-        let ptr  = alloc!(Self);
-        let node = &mut *ptr;
-
-        node.red        = true;
-        child!(node, 0) = NULL!(Self);
-        child!(node, 1) = NULL!(Self);
-        node.parent     = NULL!(Self);
-        node.key        = key.clone();
-        node.val        = val.clone();
+    unsafe fn new(cache : *mut ObjectCache<Self>, key : &K_T, val : &V_T) -> *mut Self {
+        let node = rbtree_node {
+            red      : true,
+            children : [NULL!(Self), NULL!(Self)],
+            parent   : NULL!(Self),
+            key      : key.clone(),
+            val      : val.clone()
+        };
+        if cache.is_null() {
+            return Box::into_raw(Box::new(node));
+        }
 
+        let ptr = (*cache).alloc();
+        if ptr.is_null() {
+            return ptr;
+        }
+        core::ptr::write(ptr, node);
         return ptr;
     }
 
-    unsafe fn dispose(&mut self) {
+    unsafe fn free(cache : *mut ObjectCache<Self>, node : *mut Self) {
+        if cache.is_null() {
+            drop(Box::from_raw(node));
+        } else {
+            core::ptr::drop_in_place(node);
+            (*cache).free(node);
+        }
+    }
+
+    unsafe fn walk<F : FnMut(&K_T, &V_T)>(node : *mut Self, f : &mut F) {
+        if node.is_null() { return; }
+
+        rbtree_node::walk(child!(*node, 0), f);
+        f(&(*node).key, &(*node).val);
+        rbtree_node::walk(child!(*node, 1), f);
+    }
+
+    unsafe fn dispose(&mut self, cache : *mut ObjectCache<Self>) {
         if !self.children[0].is_null() {
-            (&mut *self.children[0]).dispose();
-            free!(self.children[0]);
+            (&mut *self.children[0]).dispose(cache);
+            free!(cache, self.children[0]);
         }
         if !self.children[1].is_null() {
-            (&mut *self.children[1]).dispose();
-            free!(self.children[1]);
+            (&mut *self.children[1]).dispose(cache);
+            free!(cache, self.children[1]);
         }
     }
 
@@ -122,23 +144,31 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
 pub struct rbtree<K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debug> {
     pub root : *mut rbtree_node<K_T, V_T>,
     pub beg  : *mut rbtree_node<K_T, V_T>,
-    pub len  : usize
+    pub len  : usize,
+    /* Where nodes come from; null to box them */
+    cache    : *mut ObjectCache<rbtree_node<K_T, V_T>>
 }
 
 impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debug> rbtree<K_T, V_T> {
     pub fn new() -> Self {
+        return rbtree::with_cache(NULL!(ObjectCache<rbtree_node<K_T, V_T>>));
+    }
+
+    /* A tree that takes its nodes from `cache`, which must outlive it */
+    pub fn with_cache(cache : *mut ObjectCache<rbtree_node<K_T, V_T>>) -> Self {
         rbtree {
-            root : NULL!(rbtree_node<K_T, V_T>),
-            beg  : NULL!(rbtree_node<K_T, V_T>),
-            len  : 0
+            root  : NULL!(rbtree_node<K_T, V_T>),
+            beg   : NULL!(rbtree_node<K_T, V_T>),
+            len   : 0,
+            cache : cache
         }
     }
 
     pub fn dispose(&mut self) {
         unsafe {
             if !self.root.is_null() {
-                (*self.root).dispose();
-                free!(self.root);
+                (*self.root).dispose(self.cache);
+                free!(self.cache, self.root);
                 self.beg = NULL!(rbtree_node<K_T, V_T>);
                 self.len = 0;
             }
@@ -155,14 +185,23 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
         }
     }
 
-    pub fn insert(&mut self, key : K_T, val : V_T) {
+    /* Add `key`, or replace its value if it is already there. Returns false,
+     * leaving the tree as it was, if the cache has no node for a new key. */
+    pub fn insert(&mut self, key : K_T, val : V_T) -> bool {
         unsafe {
+            /* Take the node before changing anything */
+            let exists = self.lookup(key.clone()).is_some();
+            let fresh  = if exists { NULL!(rbtree_node<K_T, V_T>) } else { rbtree_node::new(self.cache, &key, &val) };
+            if !exists && fresh.is_null() {
+                return false;
+            }
+
             let mut node       = NULL!(rbtree_node<K_T, V_T>);
             let mut made_new   = false;
             let mut only_lefts = true;
 
             if self.root.is_null() {
-                self.root = rbtree_node::new(&key, &val);
+                self.root = fresh;
                 self.beg  = self.root;
                 node      = self.root;
                 made_new  = true;
@@ -181,7 +220,7 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
                 loop {
                     if q.is_null() {
                         /* Insert node at the first null link */
-                        q = rbtree_node::new(&key, &val);
+                        q = fresh;
                         child!(*p, dir) = q;
                         (*q).parent     = p;
                         made_new        = true;
@@ -249,6 +288,7 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
                     self.beg = node;
                 }
             }
+            return true;
         }
     }
     
@@ -278,7 +318,7 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
             if self.root.is_null() { return false; }
 
             if self.len == 1 && (*self.root).key == key {
-                free!(self.root);
+                free!(self.cache, self.root);
                 self.root = NULL!(rbtree_node<K_T, V_T>);
                 self.beg  = NULL!(rbtree_node<K_T, V_T>);
                 self.len  = 0;
@@ -373,7 +413,7 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
                     self.beg = p;
                 }
 
-                free!(q);
+                free!(self.cache, q);
 
                 q = NULL!(rbtree_node<K_T, V_T>);
             }
@@ -395,6 +435,13 @@ impl <K_T : Clone + PartialOrd + core::fmt::Debug, V_T : Clone + core::fmt::Debu
         }
     }
 
+    /* Visit every node in key order. */
+    pub fn for_each<F : FnMut(&K_T, &V_T)>(&self, mut f : F) {
+        unsafe {
+            rbtree_node::walk(self.root, &mut f);
+        }
+    }
+
     pub fn first(&self) -> Option<(&mut K_T, &mut V_T)> {
         unsafe {
             if self.beg.is_null() {
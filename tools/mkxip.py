@@ -0,0 +1,172 @@
+#!/usr/bin/env python3
+"""Pack execute-in-place user programs into the flash table of contents.
+
+Each input is a RV32 PIE linked with lds/xip.lds. The output is the blob that
+asm/progs.S includes; src/loader/xip.rs reads it. Layout (little endian):
+
+    u32 magic ("XIPT"), u32 count
+    count entries of:
+        u8  name[16]        NUL padded
+        u32 text_off        from the start of the table; run in place
+        u32 text_len
+        u32 data_off        initial .data image, copied to RAM per instance
+        u32 data_len
+        u32 bss_len
+        u32 entry           offset into text
+        u32 gp              offset into the RAM block
+        u32 reloc_off       table of u32 relocations
+        u32 reloc_count
+    then the text, data and relocation blobs, each 4-byte aligned.
+
+A relocation is the offset of a word in the RAM block. Bit 31 says the word
+holds a text offset (add the flash address of the text); otherwise it holds a
+data offset (add the RAM address of the block).
+
+usage: mkxip.py -o progs.bin [name=prog.elf ...]
+"""
+
+import os
+import struct
+import sys
+
+MAGIC = 0x54504958
+NAME_LEN = 16
+ENTRY_FMT = "<16s9I"
+TEXT_FLAG = 0x80000000
+
+PT_LOAD, PT_DYNAMIC = 1, 2
+PF_X = 1
+DT_NULL, DT_RELA, DT_RELASZ = 0, 7, 8
+R_RISCV_NONE, R_RISCV_RELATIVE = 0, 3
+SHT_SYMTAB = 2
+
+
+def fail(msg):
+    sys.exit("mkxip: " + msg)
+
+
+def load(path):
+    with open(path, "rb") as f:
+        elf = f.read()
+    if elf[:4] != b"\x7fELF" or elf[4] != 1 or elf[5] != 1:
+        fail("%s: not a little-endian ELF32 file" % path)
+    (e_type, e_machine, _, e_entry, e_phoff, e_shoff, _, _, e_phentsize,
+     e_phnum, e_shentsize, e_shnum, _) = struct.unpack_from("<HHIIIIIHHHHHH", elf, 16)
+    if e_machine != 243 or e_type != 3:
+        fail("%s: not a RISC-V PIE" % path)
+
+    text = data = dynamic = None
+    for i in range(e_phnum):
+        ph = struct.unpack_from("<8I", elf, e_phoff + i * e_phentsize)
+        if ph[0] == PT_LOAD:
+            if ph[6] & PF_X:
+                text = ph
+            else:
+                data = ph
+        elif ph[0] == PT_DYNAMIC:
+            dynamic = ph
+    if text is None:
+        fail("%s: no executable segment" % path)
+
+    # (offset, vaddr, filesz, memsz)
+    text = (text[1], text[2], text[4], text[5])
+    data = (data[1], data[2], data[4], data[5]) if data else (0, 0, 0, 0)
+    image = bytearray(elf[data[0]:data[0] + data[2]])
+
+    def in_text(a):
+        return text[1] <= a < text[1] + text[3]
+
+    def in_data(a):
+        return data[1] <= a < data[1] + data[3]
+
+    relocs = []
+    if dynamic:
+        rela = relasz = 0
+        for off in range(dynamic[1], dynamic[1] + dynamic[4], 8):
+            tag, val = struct.unpack_from("<II", elf, off)
+            if tag == DT_NULL:
+                break
+            if tag == DT_RELA:
+                rela = val
+            elif tag == DT_RELASZ:
+                relasz = val
+        if rela:
+            base = rela - data[1] + data[0]
+            for off in range(base, base + relasz, 12):
+                r_offset, r_info, r_addend = struct.unpack_from("<IIi", elf, off)
+                kind = r_info & 0xFF
+                if kind == R_RISCV_NONE:
+                    continue
+                if kind != R_RISCV_RELATIVE:
+                    fail("%s: unsupported relocation type %d" % (path, kind))
+                if not in_data(r_offset):
+                    fail("%s: relocation in text at %#x" % (path, r_offset))
+                word = r_offset - data[1]
+                if in_text(r_addend):
+                    struct.pack_into("<I", image, word, r_addend - text[1])
+                    relocs.append(word | TEXT_FLAG)
+                elif in_data(r_addend) or r_addend == data[1] + data[3]:
+                    struct.pack_into("<I", image, word, r_addend - data[1])
+                    relocs.append(word)
+                else:
+                    fail("%s: relocation target %#x outside the image" % (path, r_addend))
+
+    gp = 0
+    for i in range(e_shnum):
+        sh = struct.unpack_from("<10I", elf, e_shoff + i * e_shentsize)
+        if sh[1] != SHT_SYMTAB:
+            continue
+        strtab = struct.unpack_from("<10I", elf, e_shoff + sh[6] * e_shentsize)
+        for off in range(sh[4], sh[4] + sh[5], 16):
+            st_name, st_value = struct.unpack_from("<II", elf, off)
+            name_off = strtab[4] + st_name
+            if elf[name_off:elf.index(b"\0", name_off)] == b"__global_pointer$":
+                gp = st_value - data[1]
+
+    return {
+        "text": elf[text[0]:text[0] + text[2]],
+        "data": bytes(image),
+        "bss": data[3] - data[2],
+        "entry": e_entry - text[1],
+        "gp": gp,
+        "relocs": relocs,
+    }
+
+
+def align4(blob):
+    return blob + b"\0" * (-len(blob) % 4)
+
+
+def main(argv):
+    if len(argv) < 2 or argv[0] != "-o":
+        fail("usage: mkxip.py -o progs.bin [name=prog.elf ...]")
+    out, inputs = argv[1], argv[2:]
+
+    progs = []
+    for arg in inputs:
+        name, _, path = arg.rpartition("=")
+        name = name or os.path.splitext(os.path.basename(path))[0]
+        if len(name) >= NAME_LEN:
+            fail("%s: name longer than %d bytes" % (name, NAME_LEN - 1))
+        progs.append((name, load(path)))
+
+    offset = 8 + len(progs) * struct.calcsize(ENTRY_FMT)
+    header = struct.pack("<II", MAGIC, len(progs))
+    blobs = b""
+    for name, p in progs:
+        text_off = offset + len(blobs)
+        blobs += align4(p["text"])
+        data_off = offset + len(blobs)
+        blobs += align4(p["data"])
+        reloc_off = offset + len(blobs)
+        blobs += b"".join(struct.pack("<I", r) for r in p["relocs"])
+        header += struct.pack(ENTRY_FMT, name.encode(), text_off, len(p["text"]),
+                              data_off, len(p["data"]), p["bss"], p["entry"],
+                              p["gp"], reloc_off, len(p["relocs"]))
+
+    with open(out, "wb") as f:
+        f.write(header + blobs)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])